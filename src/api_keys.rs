@@ -0,0 +1,172 @@
+use hyper::{Body, Request, Response};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    crypto,
+    errors::Error,
+    permissions::Node,
+    structs::{ApiKey, ApiKeyScope},
+    Api,
+};
+
+/// Every API key starts with this prefix, which is how `authenticate` tells
+/// them apart from JWTs. Keys are `bmk_<id>.<secret>`.
+pub const API_KEY_PREFIX: &str = "bmk_";
+
+#[derive(Debug, Deserialize)]
+pub struct NewApiKey {
+    pub name: String,
+    pub scopes: Vec<ApiKeyScope>,
+}
+
+/// An API key as shown to its owner, without the secret hash.
+#[derive(Debug, Serialize)]
+pub struct ApiKeyInfo {
+    pub id: String,
+    pub name: String,
+    pub scopes: Vec<ApiKeyScope>,
+    pub created_at: i64,
+}
+
+impl From<ApiKey> for ApiKeyInfo {
+    fn from(key: ApiKey) -> Self {
+        Self {
+            id: key.id,
+            name: key.name,
+            scopes: key.scopes,
+            created_at: key.created_at.timestamp_millis() / 1000,
+        }
+    }
+}
+
+/// The response to creating a key, the only time the full key is shown.
+#[derive(Debug, Serialize)]
+pub struct CreatedApiKey {
+    pub key: String,
+    #[serde(flatten)]
+    pub info: ApiKeyInfo,
+}
+
+impl Api {
+    /// Looks up the key behind `token`, returning `None` when it does not
+    /// exist, was revoked or the secret does not match.
+    pub async fn verify_api_key(&self, token: &str) -> Result<Option<ApiKey>, Error> {
+        let (id, secret) = match token
+            .strip_prefix(API_KEY_PREFIX)
+            .and_then(|rest| rest.split_once('.'))
+        {
+            Some(parts) => parts,
+            None => return Ok(None),
+        };
+
+        let key = match self.db.get_api_key(id).await? {
+            Some(key) => key,
+            None => return Ok(None),
+        };
+
+        if key.revoked || key.hash != crypto::hash_token(secret) {
+            return Ok(None);
+        }
+
+        Ok(Some(key))
+    }
+
+    pub async fn get_api_keys(
+        &self,
+        req: Request<Body>,
+        user_id: &str,
+    ) -> Result<Response<Body>, hyper::Error> {
+        let keys = match self.db.get_api_keys(user_id).await {
+            Ok(keys) => keys,
+            Err(e) => {
+                tracing::error!("error getting api keys: {}", e);
+                return self.internal_server_error().await;
+            }
+        };
+
+        let keys: Vec<ApiKeyInfo> = keys.into_iter().map(ApiKeyInfo::from).collect();
+
+        self.json_response(req, keys).await
+    }
+
+    pub async fn create_api_key(
+        &self,
+        req: Request<Body>,
+        user_id: &str,
+    ) -> Result<Response<Body>, hyper::Error> {
+        let (parts, body) = req.into_parts();
+        let body_bytes = match hyper::body::to_bytes(body).await {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                tracing::error!("error reading body: {}", e);
+                return self.internal_server_error().await;
+            }
+        };
+
+        let new: NewApiKey = match serde_json::from_slice(&body_bytes) {
+            Ok(new) => new,
+            Err(e) => {
+                tracing::error!("error parsing api key: {}", e);
+                return self.bad_request("invalid api key").await;
+            }
+        };
+
+        if new.scopes.is_empty() {
+            return self
+                .bad_request("an api key needs at least one scope")
+                .await;
+        }
+
+        for permission in new.scopes.iter().flat_map(|scope| &scope.permissions) {
+            if let Err(e) = permission.parse::<Node>() {
+                return self.bad_request(&e).await;
+            }
+        }
+
+        let id = crypto::random_token(12);
+        let secret = crypto::random_token(32);
+
+        let key = ApiKey {
+            id: id.clone(),
+            name: new.name,
+            owner_id: user_id.to_string(),
+            hash: crypto::hash_token(&secret),
+            scopes: new.scopes,
+            created_at: bson::DateTime::now(),
+            revoked: false,
+        };
+
+        if let Err(e) = self.db.create_api_key(key.clone()).await {
+            tracing::error!("error creating api key: {}", e);
+            return self.internal_server_error().await;
+        }
+
+        let created = CreatedApiKey {
+            key: format!("{}{}.{}", API_KEY_PREFIX, id, secret),
+            info: key.into(),
+        };
+
+        let mut resp = self
+            .json_response(Request::from_parts(parts, Body::empty()), created)
+            .await?;
+        *resp.status_mut() = hyper::StatusCode::CREATED;
+
+        Ok(resp)
+    }
+
+    pub async fn delete_api_key(
+        &self,
+        _req: Request<Body>,
+        id: &str,
+        user_id: &str,
+    ) -> Result<Response<Body>, hyper::Error> {
+        match self.db.revoke_api_key(id, user_id).await {
+            Ok(true) => Ok(Response::builder().status(204).body(Body::empty()).unwrap()),
+            Ok(false) => self.not_found().await,
+            Err(e) => {
+                tracing::error!("error revoking api key: {}", e);
+                self.internal_server_error().await
+            }
+        }
+    }
+}
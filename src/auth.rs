@@ -1,49 +1,580 @@
-use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use std::{collections::HashMap, sync::RwLock};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use ed25519_dalek::pkcs8::DecodePrivateKey;
+use jsonwebtoken::{
+    jwk::{
+        AlgorithmParameters, CommonParameters, EllipticCurve, Jwk, JwkSet, KeyAlgorithm,
+        OctetKeyPairParameters, OctetKeyPairType, PublicKeyUse, RSAKeyParameters, RSAKeyType,
+    },
+    Algorithm, DecodingKey, EncodingKey, Header, Validation,
+};
+use rsa::{pkcs1::DecodeRsaPrivateKey, traits::PublicKeyParts};
 use serde::{Deserialize, Serialize};
 
-use crate::errors::Error;
+use crate::{crypto, discord::DiscordUser, errors::Error, structs::ApiKey};
+
+/// How long an access token stays valid. Sessions outlive their access
+/// tokens through refresh tokens, see `sessions.rs`.
+pub const ACCESS_TOKEN_TTL: i64 = 15 * 60;
 
-#[derive(Debug, Serialize, Deserialize)]
+/// The `kid` used when the API is configured with a single `JWT_SECRET`.
+const DEFAULT_KID: &str = "default";
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Claims {
     pub sub: String,
+    pub iss: String,
+    pub aud: String,
+    pub iat: usize,
+    pub nbf: usize,
     pub exp: usize,
+    pub jti: String,
+    pub sid: String,
+    /// The Discord profile at the time the token was issued, so clients can
+    /// render the user without asking Discord.
+    pub username: String,
+    pub global_name: Option<String>,
+    pub avatar: Option<String>,
+    /// Black Mesa staff bypass guild permission checks.
+    #[serde(default)]
+    pub staff: bool,
 }
 
-pub struct Jwt {
+/// Who a request is made by: a user logged in through Discord, or an API key
+/// acting within its scopes.
+#[derive(Clone, Debug)]
+pub enum Principal {
+    User(Claims),
+    ApiKey(ApiKey),
+}
+
+impl Principal {
+    /// The user the request is made by, `None` for API keys.
+    pub fn user_id(&self) -> Option<&str> {
+        match self {
+            Principal::User(claims) => Some(&claims.sub),
+            Principal::ApiKey(_) => None,
+        }
+    }
+}
+
+/// The signing key set, as read from `JWT_KEYS_FILE`:
+///
+/// ```json
+/// {
+///     "current": "2024-06",
+///     "keys": [
+///         { "kid": "2024-06", "algorithm": "EdDSA", "private_key_file": "/keys/2024-06.pem" },
+///         { "kid": "2024-03", "algorithm": "RS256", "private_key_file": "/keys/2024-03.pem" },
+///         { "kid": "2023-12", "secret": "..." }
+///     ]
+/// }
+/// ```
+///
+/// New tokens are signed with `current`, every listed key is accepted for
+/// verification. Rotating means adding a key and making it `current`, then
+/// dropping the old key once the tokens it signed have expired.
+///
+/// Keys default to `HS256` with a shared `secret`. `RS256` and `EdDSA` keys
+/// are read from a PKCS#8 (or, for RSA, PKCS#1) PEM private key instead, and
+/// their public halves are published at `/.well-known/jwks.json`.
+#[derive(Debug, Deserialize)]
+pub struct JwtKeys {
+    pub current: String,
+    pub keys: Vec<JwtKey>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct JwtKey {
+    pub kid: String,
+    #[serde(default = "default_algorithm")]
+    pub algorithm: Algorithm,
+    pub secret: Option<String>,
+    pub private_key_file: Option<String>,
+}
+
+fn default_algorithm() -> Algorithm {
+    Algorithm::HS256
+}
+
+impl JwtKeys {
+    /// Loads the key set from `JWT_KEYS_FILE`, falling back to a single key
+    /// from `JWT_SECRET`.
+    pub fn from_env() -> Result<Self, Error> {
+        if let Ok(path) = std::env::var("JWT_KEYS_FILE") {
+            let file = std::fs::read_to_string(path)?;
+            return Ok(serde_json::from_str(&file)?);
+        }
+
+        match std::env::var("JWT_SECRET") {
+            Ok(secret) => Ok(Self {
+                current: DEFAULT_KID.to_string(),
+                keys: vec![JwtKey {
+                    kid: DEFAULT_KID.to_string(),
+                    algorithm: Algorithm::HS256,
+                    secret: Some(secret),
+                    private_key_file: None,
+                }],
+            }),
+            Err(_) => Err(Error::from(
+                "JWT_KEYS_FILE or JWT_SECRET must be set".to_string(),
+            )),
+        }
+    }
+}
+
+/// A key loaded from a `JwtKey`, along with its public JWK when it has one.
+struct LoadedKey {
+    algorithm: Algorithm,
     key: EncodingKey,
     dec_key: DecodingKey,
+    jwk: Option<Jwk>,
+}
+
+fn public_jwk(kid: &str, algorithm: KeyAlgorithm, params: AlgorithmParameters) -> Jwk {
+    Jwk {
+        common: CommonParameters {
+            public_key_use: Some(PublicKeyUse::Signature),
+            key_algorithm: Some(algorithm),
+            key_id: Some(kid.to_string()),
+            ..Default::default()
+        },
+        algorithm: params,
+    }
+}
+
+impl TryFrom<&JwtKey> for LoadedKey {
+    type Error = Error;
+
+    fn try_from(key: &JwtKey) -> Result<Self, Self::Error> {
+        let pem = || match &key.private_key_file {
+            Some(path) => Ok(std::fs::read_to_string(path)?),
+            None => Err(Error::from(format!(
+                "key {} needs a private_key_file",
+                key.kid
+            ))),
+        };
+
+        match key.algorithm {
+            Algorithm::HS256 => {
+                let secret = match &key.secret {
+                    Some(secret) => secret.as_bytes(),
+                    None => return Err(Error::from(format!("key {} needs a secret", key.kid))),
+                };
+
+                Ok(Self {
+                    algorithm: key.algorithm,
+                    key: EncodingKey::from_secret(secret),
+                    dec_key: DecodingKey::from_secret(secret),
+                    jwk: None,
+                })
+            }
+            Algorithm::RS256 => {
+                let pem = pem()?;
+                let private = rsa::RsaPrivateKey::from_pkcs8_pem(&pem)
+                    .or_else(|_| rsa::RsaPrivateKey::from_pkcs1_pem(&pem))
+                    .map_err(|e| Error::from(format!("invalid RSA key {}: {}", key.kid, e)))?;
+
+                let n = URL_SAFE_NO_PAD.encode(private.n().to_bytes_be());
+                let e = URL_SAFE_NO_PAD.encode(private.e().to_bytes_be());
+
+                Ok(Self {
+                    algorithm: key.algorithm,
+                    key: EncodingKey::from_rsa_pem(pem.as_bytes())?,
+                    dec_key: DecodingKey::from_rsa_components(&n, &e)?,
+                    jwk: Some(public_jwk(
+                        &key.kid,
+                        KeyAlgorithm::RS256,
+                        AlgorithmParameters::RSA(RSAKeyParameters {
+                            key_type: RSAKeyType::RSA,
+                            n,
+                            e,
+                        }),
+                    )),
+                })
+            }
+            Algorithm::EdDSA => {
+                let pem = pem()?;
+                let private = ed25519_dalek::SigningKey::from_pkcs8_pem(&pem)
+                    .map_err(|e| Error::from(format!("invalid Ed25519 key {}: {}", key.kid, e)))?;
+
+                let x = URL_SAFE_NO_PAD.encode(private.verifying_key().to_bytes());
+
+                Ok(Self {
+                    algorithm: key.algorithm,
+                    key: EncodingKey::from_ed_pem(pem.as_bytes())?,
+                    dec_key: DecodingKey::from_ed_components(&x)?,
+                    jwk: Some(public_jwk(
+                        &key.kid,
+                        KeyAlgorithm::EdDSA,
+                        AlgorithmParameters::OctetKeyPair(OctetKeyPairParameters {
+                            key_type: OctetKeyPairType::OctetKeyPair,
+                            curve: EllipticCurve::Ed25519,
+                            x,
+                        }),
+                    )),
+                })
+            }
+            algorithm => Err(Error::from(format!(
+                "key {} uses unsupported algorithm {:?}",
+                key.kid, algorithm
+            ))),
+        }
+    }
+}
+
+struct KeyRing {
+    current: String,
+    keys: HashMap<String, LoadedKey>,
+}
+
+impl TryFrom<JwtKeys> for KeyRing {
+    type Error = Error;
+
+    fn try_from(keys: JwtKeys) -> Result<Self, Self::Error> {
+        let loaded = keys
+            .keys
+            .iter()
+            .map(|key| Ok((key.kid.clone(), LoadedKey::try_from(key)?)))
+            .collect::<Result<HashMap<_, _>, Error>>()?;
+
+        if !loaded.contains_key(&keys.current) {
+            return Err(Error::from(format!(
+                "current key {} is not in the key set",
+                keys.current
+            )));
+        }
+
+        Ok(Self {
+            current: keys.current,
+            keys: loaded,
+        })
+    }
+}
+
+pub struct Jwt {
+    ring: RwLock<KeyRing>,
+    issuer: String,
+    audience: String,
 }
 
 impl Jwt {
-    pub fn new(secret: &[u8]) -> Self {
-        Self {
-            key: EncodingKey::from_secret(secret),
-            dec_key: DecodingKey::from_secret(secret),
+    /// Tokens are issued with `issuer` and `audience`, and tokens that name
+    /// anything else are rejected, e.g. ones minted for another environment
+    /// that shares a signing key.
+    pub fn new(keys: JwtKeys, issuer: String, audience: String) -> Result<Self, Error> {
+        Ok(Self {
+            ring: RwLock::new(keys.try_into()?),
+            issuer,
+            audience,
+        })
+    }
+
+    /// Swaps in a new key set. Tokens signed with keys that are still in the
+    /// set stay valid.
+    pub fn reload(&self, keys: JwtKeys) -> Result<(), Error> {
+        let ring: KeyRing = keys.try_into()?;
+        tracing::info!(
+            "Loaded {} JWT keys, signing with {}",
+            ring.keys.len(),
+            ring.current
+        );
+
+        *self.ring.write().unwrap() = ring;
+
+        Ok(())
+    }
+
+    /// The public keys of every asymmetric key in the set.
+    pub fn jwks(&self) -> JwkSet {
+        let ring = self.ring.read().unwrap();
+
+        JwkSet {
+            keys: ring
+                .keys
+                .values()
+                .filter_map(|key| key.jwk.clone())
+                .collect(),
         }
     }
 
-    pub fn create_jwt(&self, user_id: &str) -> Result<(String, usize), Error> {
-        let exp = match chrono::Utc::now().checked_add_days(chrono::Days::new(7)) {
-            Some(exp) => exp.timestamp(),
-            None => chrono::Utc::now().timestamp() + 604800,
-        } as usize;
+    pub fn create_jwt(
+        &self,
+        user: &DiscordUser,
+        session_id: &str,
+        staff: bool,
+    ) -> Result<(String, Claims), Error> {
+        let now = chrono::Utc::now().timestamp();
 
         let claims = Claims {
-            sub: user_id.to_string(),
-            exp,
+            sub: user.id.clone(),
+            iss: self.issuer.clone(),
+            aud: self.audience.clone(),
+            iat: now as usize,
+            nbf: now as usize,
+            exp: (now + ACCESS_TOKEN_TTL) as usize,
+            jti: crypto::random_token(16),
+            sid: session_id.to_string(),
+            username: user.username.clone(),
+            global_name: user.global_name.clone(),
+            avatar: user.avatar.clone(),
+            staff,
         };
 
-        let jwt = jsonwebtoken::encode(&Header::new(Algorithm::HS256), &claims, &self.key)?;
+        let ring = self.ring.read().unwrap();
+        let key = &ring.keys[&ring.current];
+
+        let mut header = Header::new(key.algorithm);
+        header.kid = Some(ring.current.clone());
+
+        let jwt = jsonwebtoken::encode(&header, &claims, &key.key)?;
 
-        Ok((jwt, exp))
+        Ok((jwt, claims))
     }
 
     pub fn verify_jwt(&self, jwt: &str) -> Result<Claims, Error> {
-        let validation = Validation::new(Algorithm::HS256);
+        let header = jsonwebtoken::decode_header(jwt)?;
 
-        match jsonwebtoken::decode::<Claims>(jwt, &self.dec_key, &validation) {
+        let kid = match header.kid {
+            Some(kid) => kid,
+            None => return Err(Error::from("token has no kid".to_string())),
+        };
+
+        let ring = self.ring.read().unwrap();
+
+        let key = match ring.keys.get(&kid) {
+            Some(key) => key,
+            None => return Err(Error::from(format!("unknown signing key {}", kid))),
+        };
+
+        // pinned to the key's own algorithm, never the one the header claims
+        let mut validation = Validation::new(key.algorithm);
+        validation.set_issuer(&[&self.issuer]);
+        validation.set_audience(&[&self.audience]);
+        validation.set_required_spec_claims(&["exp", "nbf", "iss", "aud", "sub"]);
+        validation.validate_nbf = true;
+
+        match jsonwebtoken::decode::<Claims>(jwt, &key.dec_key, &validation) {
             Ok(token) => Ok(token.claims),
             Err(e) => Err(Error::from(e)),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "test secret";
+
+    fn hs256(kid: &str, secret: &str) -> JwtKey {
+        JwtKey {
+            kid: kid.to_string(),
+            algorithm: Algorithm::HS256,
+            secret: Some(secret.to_string()),
+            private_key_file: None,
+        }
+    }
+
+    fn pem_key(kid: &str, algorithm: Algorithm, file: &str) -> JwtKey {
+        JwtKey {
+            kid: kid.to_string(),
+            algorithm,
+            secret: None,
+            private_key_file: Some(format!(
+                "{}/tests/keys/{}",
+                env!("CARGO_MANIFEST_DIR"),
+                file
+            )),
+        }
+    }
+
+    fn keys(current: &str, keys: Vec<JwtKey>) -> JwtKeys {
+        JwtKeys {
+            current: current.to_string(),
+            keys,
+        }
+    }
+
+    fn jwt(keys: JwtKeys) -> Jwt {
+        Jwt::new(keys, "black-mesa".to_string(), "black-mesa".to_string()).unwrap()
+    }
+
+    fn user() -> DiscordUser {
+        DiscordUser {
+            id: "1".to_string(),
+            username: "gordon".to_string(),
+            discriminator: "0".to_string(),
+            global_name: None,
+            avatar: None,
+        }
+    }
+
+    fn sign(jwt: &Jwt) -> String {
+        jwt.create_jwt(&user(), "sid", false).unwrap().0
+    }
+
+    /// Signs `claims` with the shared test secret, as whatever `kid` says.
+    fn forge(kid: Option<&str>, claims: &Claims) -> String {
+        let mut header = Header::new(Algorithm::HS256);
+        header.kid = kid.map(str::to_string);
+
+        jsonwebtoken::encode(
+            &header,
+            claims,
+            &EncodingKey::from_secret(SECRET.as_bytes()),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn signs_with_current_and_verifies_retired_keys() {
+        let jwt = jwt(keys("old", vec![hs256("old", "old secret")]));
+        let old_token = sign(&jwt);
+
+        jwt.reload(keys(
+            "new",
+            vec![hs256("new", "new secret"), hs256("old", "old secret")],
+        ))
+        .unwrap();
+        let new_token = sign(&jwt);
+
+        let header = jsonwebtoken::decode_header(&new_token).unwrap();
+        assert_eq!(header.kid.as_deref(), Some("new"));
+        assert_eq!(jwt.verify_jwt(&new_token).unwrap().sub, "1");
+        assert_eq!(jwt.verify_jwt(&old_token).unwrap().sub, "1");
+
+        // dropping the retired key ends its tokens
+        jwt.reload(keys("new", vec![hs256("new", "new secret")]))
+            .unwrap();
+        assert!(jwt.verify_jwt(&old_token).is_err());
+        assert!(jwt.verify_jwt(&new_token).is_ok());
+    }
+
+    #[test]
+    fn rejects_unknown_and_missing_kids() {
+        let jwt = jwt(keys("default", vec![hs256("default", SECRET)]));
+        let (_, claims) = jwt.create_jwt(&user(), "sid", false).unwrap();
+
+        assert!(jwt.verify_jwt(&forge(Some("default"), &claims)).is_ok());
+        assert!(jwt.verify_jwt(&forge(Some("other"), &claims)).is_err());
+        assert!(jwt.verify_jwt(&forge(None, &claims)).is_err());
+    }
+
+    #[test]
+    fn pins_the_algorithm_to_the_key() {
+        let jwt = jwt(keys(
+            "rsa",
+            vec![pem_key("rsa", Algorithm::RS256, "rsa.pem")],
+        ));
+        let (_, claims) = jwt.create_jwt(&user(), "sid", false).unwrap();
+
+        // an HS256 token under the RSA key's kid, signed with its public modulus
+        let modulus = match &jwt.jwks().keys[0].algorithm {
+            AlgorithmParameters::RSA(params) => params.n.clone(),
+            _ => unreachable!(),
+        };
+        let mut header = Header::new(Algorithm::HS256);
+        header.kid = Some("rsa".to_string());
+        let forged = jsonwebtoken::encode(
+            &header,
+            &claims,
+            &EncodingKey::from_secret(modulus.as_bytes()),
+        )
+        .unwrap();
+
+        assert!(jwt.verify_jwt(&forged).is_err());
+    }
+
+    #[test]
+    fn round_trips_asymmetric_keys_and_publishes_them() {
+        let all = || {
+            vec![
+                pem_key("rsa", Algorithm::RS256, "rsa.pem"),
+                pem_key("ed", Algorithm::EdDSA, "ed25519.pem"),
+                hs256("hs", SECRET),
+            ]
+        };
+
+        for (kid, algorithm) in [("rsa", Algorithm::RS256), ("ed", Algorithm::EdDSA)] {
+            let jwt = jwt(keys(kid, all()));
+            let token = sign(&jwt);
+
+            let header = jsonwebtoken::decode_header(&token).unwrap();
+            assert_eq!(header.alg, algorithm);
+            assert_eq!(jwt.verify_jwt(&token).unwrap().sub, "1");
+
+            // other services verify with nothing but the published key
+            let jwks = jwt.jwks();
+            let jwk = jwks.find(kid).unwrap();
+            let mut validation = Validation::new(algorithm);
+            validation.set_audience(&["black-mesa"]);
+            assert!(jsonwebtoken::decode::<Claims>(
+                &token,
+                &DecodingKey::from_jwk(jwk).unwrap(),
+                &validation
+            )
+            .is_ok());
+        }
+
+        let jwks = jwt(keys("hs", all())).jwks();
+        let mut published: Vec<_> = jwks
+            .keys
+            .iter()
+            .map(|jwk| {
+                assert_eq!(jwk.common.public_key_use, Some(PublicKeyUse::Signature));
+                (
+                    jwk.common.key_id.clone().unwrap(),
+                    jwk.common.key_algorithm.unwrap(),
+                )
+            })
+            .collect();
+        published.sort_by(|a, b| a.0.cmp(&b.0));
+
+        // the shared secret is never published
+        assert_eq!(
+            published,
+            [
+                ("ed".to_string(), KeyAlgorithm::EdDSA),
+                ("rsa".to_string(), KeyAlgorithm::RS256)
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_other_issuers_and_audiences() {
+        let token = sign(&jwt(keys("default", vec![hs256("default", SECRET)])));
+
+        let other_issuer = Jwt::new(
+            keys("default", vec![hs256("default", SECRET)]),
+            "staging".to_string(),
+            "black-mesa".to_string(),
+        )
+        .unwrap();
+        assert!(other_issuer.verify_jwt(&token).is_err());
+
+        let other_audience = Jwt::new(
+            keys("default", vec![hs256("default", SECRET)]),
+            "black-mesa".to_string(),
+            "dashboard".to_string(),
+        )
+        .unwrap();
+        assert!(other_audience.verify_jwt(&token).is_err());
+    }
+
+    #[test]
+    fn rejects_tokens_before_nbf_and_after_exp() {
+        let jwt = jwt(keys("default", vec![hs256("default", SECRET)]));
+        let (_, claims) = jwt.create_jwt(&user(), "sid", false).unwrap();
+        let now = chrono::Utc::now().timestamp() as usize;
+
+        let mut early = claims.clone();
+        early.nbf = now + 3600;
+        assert!(jwt.verify_jwt(&forge(Some("default"), &early)).is_err());
+
+        let mut expired = claims;
+        expired.exp = now - 3600;
+        assert!(jwt.verify_jwt(&forge(Some("default"), &expired)).is_err());
+    }
+}
@@ -0,0 +1,232 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use redis::AsyncCommands;
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{
+    discord::{DiscordChannel, DiscordPartialGuild, DiscordRole, DiscordUser},
+    errors::Error,
+    Api,
+};
+
+/// How long a cached Discord profile is used before it is read from the
+/// database again.
+pub const DISCORD_USER_TTL: Duration = Duration::from_secs(60 * 60);
+/// How long the guilds of a user are trusted before asking Discord again.
+pub const USER_GUILDS_TTL: Duration = Duration::from_secs(5 * 60);
+/// How long the channels and roles of a guild are trusted before asking
+/// Discord again.
+pub const GUILD_STRUCTURE_TTL: Duration = Duration::from_secs(5 * 60);
+/// How long the roles of a guild member are trusted before asking Discord again.
+pub const MEMBER_ROLES_TTL: Duration = Duration::from_secs(5 * 60);
+
+/// Expired in-process entries are only dropped when read, so they are swept
+/// once the cache grows past this many entries.
+const MEMORY_SWEEP_THRESHOLD: usize = 10_000;
+
+pub fn discord_user_key(user_id: &str) -> String {
+    format!("discord_user:{}", user_id)
+}
+
+pub fn user_guilds_key(user_id: &str) -> String {
+    format!("user_guilds:{}", user_id)
+}
+
+pub fn guild_channels_key(guild_id: &str) -> String {
+    format!("guild_channels:{}", guild_id)
+}
+
+pub fn guild_roles_key(guild_id: &str) -> String {
+    format!("guild_roles:{}", guild_id)
+}
+
+pub fn member_roles_key(guild_id: &str, user_id: &str) -> String {
+    format!("member_roles:{}:{}", guild_id, user_id)
+}
+
+/// Caches Discord data that is expensive or rate limited to fetch. Values are
+/// stored as JSON in Redis when `REDIS_URI` is set, and in process otherwise.
+///
+/// The cache is best effort: a failing Redis is logged and treated as a miss,
+/// so requests fall back to the source of the data.
+pub enum Cache {
+    Redis(redis::aio::ConnectionManager),
+    Memory(Mutex<HashMap<String, (Instant, String)>>),
+}
+
+impl Cache {
+    pub async fn new(uri: Option<&str>) -> Self {
+        let uri = match uri {
+            Some(uri) => uri,
+            None => {
+                tracing::info!("REDIS_URI is not set, caching in memory");
+                return Cache::Memory(Mutex::new(HashMap::new()));
+            }
+        };
+
+        let client = redis::Client::open(uri).expect("Invalid REDIS_URI");
+        let manager = redis::aio::ConnectionManager::new(client)
+            .await
+            .expect("Failed to connect to Redis");
+
+        Cache::Redis(manager)
+    }
+
+    pub async fn get<T: DeserializeOwned>(&self, key: &str) -> Option<T> {
+        let value: Option<String> = match self {
+            Cache::Redis(manager) => match manager.clone().get(key).await {
+                Ok(value) => value,
+                Err(err) => {
+                    tracing::warn!("Error reading {} from cache: {}", key, err);
+                    None
+                }
+            },
+            Cache::Memory(entries) => {
+                let mut entries = entries.lock().unwrap();
+
+                match entries.get(key) {
+                    Some((expires_at, _)) if *expires_at <= Instant::now() => {
+                        entries.remove(key);
+                        None
+                    }
+                    Some((_, value)) => Some(value.clone()),
+                    None => None,
+                }
+            }
+        };
+
+        value.and_then(|value| serde_json::from_str(&value).ok())
+    }
+
+    pub async fn set<T: Serialize>(&self, key: &str, value: &T, ttl: Duration) {
+        let value = match serde_json::to_string(value) {
+            Ok(value) => value,
+            Err(err) => {
+                tracing::warn!("Error serializing {} for cache: {}", key, err);
+                return;
+            }
+        };
+
+        match self {
+            Cache::Redis(manager) => {
+                let res: redis::RedisResult<()> = manager
+                    .clone()
+                    .set_ex(key, value, ttl.as_secs() as usize)
+                    .await;
+
+                if let Err(err) = res {
+                    tracing::warn!("Error writing {} to cache: {}", key, err);
+                }
+            }
+            Cache::Memory(entries) => {
+                let mut entries = entries.lock().unwrap();
+                let now = Instant::now();
+
+                if entries.len() >= MEMORY_SWEEP_THRESHOLD {
+                    entries.retain(|_, (expires_at, _)| *expires_at > now);
+                }
+
+                entries.insert(key.to_string(), (now + ttl, value));
+            }
+        }
+    }
+
+    /// Drops a cached value, so the next read goes to the source.
+    pub async fn invalidate(&self, key: &str) {
+        match self {
+            Cache::Redis(manager) => {
+                let res: redis::RedisResult<()> = manager.clone().del(key).await;
+
+                if let Err(err) = res {
+                    tracing::warn!("Error invalidating {} in cache: {}", key, err);
+                }
+            }
+            Cache::Memory(entries) => {
+                entries.lock().unwrap().remove(key);
+            }
+        }
+    }
+}
+
+impl Api {
+    /// The Discord profile of `user_id` as of their last login.
+    pub async fn discord_user(&self, user_id: &str) -> Result<Option<DiscordUser>, Error> {
+        let key = discord_user_key(user_id);
+
+        if let Some(user) = self.cache.get(&key).await {
+            return Ok(Some(user));
+        }
+
+        let user = self.db.get_discord_user(user_id).await?;
+
+        if let Some(user) = &user {
+            self.cache.set(&key, user, DISCORD_USER_TTL).await;
+        }
+
+        Ok(user)
+    }
+
+    /// The guilds `user_id` is in. Returns `None` when their Discord session
+    /// has expired.
+    pub async fn user_guilds(
+        &self,
+        user_id: &str,
+    ) -> Result<Option<Vec<DiscordPartialGuild>>, Error> {
+        let key = user_guilds_key(user_id);
+
+        if let Some(guilds) = self.cache.get(&key).await {
+            return Ok(Some(guilds));
+        }
+
+        let token = match self.discord_access_token(user_id).await? {
+            Some(token) => token,
+            None => return Ok(None),
+        };
+
+        let guilds = self.discord.user_guilds(&token).await?;
+        self.cache.set(&key, &guilds, USER_GUILDS_TTL).await;
+
+        Ok(Some(guilds))
+    }
+
+    /// The channels of `guild_id`. Returns `None` when the bot is not in it.
+    pub async fn guild_channels(
+        &self,
+        guild_id: &str,
+    ) -> Result<Option<Vec<DiscordChannel>>, Error> {
+        let key = guild_channels_key(guild_id);
+
+        if let Some(channels) = self.cache.get(&key).await {
+            return Ok(Some(channels));
+        }
+
+        let channels = self.discord.bot_channels(guild_id).await?;
+
+        if let Some(channels) = &channels {
+            self.cache.set(&key, channels, GUILD_STRUCTURE_TTL).await;
+        }
+
+        Ok(channels)
+    }
+
+    /// The roles of `guild_id`. Returns `None` when the bot is not in it.
+    pub async fn guild_roles(&self, guild_id: &str) -> Result<Option<Vec<DiscordRole>>, Error> {
+        let key = guild_roles_key(guild_id);
+
+        if let Some(roles) = self.cache.get(&key).await {
+            return Ok(Some(roles));
+        }
+
+        let roles = self.discord.bot_roles(guild_id).await?;
+
+        if let Some(roles) = &roles {
+            self.cache.set(&key, roles, GUILD_STRUCTURE_TTL).await;
+        }
+
+        Ok(roles)
+    }
+}
@@ -0,0 +1,168 @@
+use aes_gcm::{
+    aead::{Aead, KeyInit, Payload},
+    Aes256Gcm, Nonce,
+};
+use base64::{
+    engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+    Engine,
+};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+
+use crate::errors::Error;
+
+const NONCE_LEN: usize = 12;
+
+/// Encrypts secrets that are stored at rest, such as Discord OAuth tokens.
+///
+/// Ciphertexts are base64 encoded as `nonce || ciphertext` and bound to an
+/// associated value (e.g. the owning user ID), so a stored secret cannot be
+/// moved onto another record.
+pub struct TokenCipher {
+    cipher: Aes256Gcm,
+}
+
+impl TokenCipher {
+    /// Creates a cipher from a base64 encoded 256-bit key.
+    pub fn new(key: &str) -> Result<Self, Error> {
+        let key = STANDARD
+            .decode(key)
+            .map_err(|e| Error::from(format!("invalid token key: {}", e)))?;
+
+        let cipher = Aes256Gcm::new_from_slice(&key)
+            .map_err(|_| Error::from("token key must be 32 bytes".to_string()))?;
+
+        Ok(Self { cipher })
+    }
+
+    pub fn encrypt(&self, plaintext: &str, associated: &str) -> Result<String, Error> {
+        let mut nonce = [0u8; NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut nonce);
+
+        let ciphertext = self
+            .cipher
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: plaintext.as_bytes(),
+                    aad: associated.as_bytes(),
+                },
+            )
+            .map_err(|_| Error::from("failed to encrypt token".to_string()))?;
+
+        let mut out = nonce.to_vec();
+        out.extend(ciphertext);
+
+        Ok(STANDARD.encode(out))
+    }
+
+    pub fn decrypt(&self, encoded: &str, associated: &str) -> Result<String, Error> {
+        let data = STANDARD
+            .decode(encoded)
+            .map_err(|e| Error::from(format!("invalid encrypted token: {}", e)))?;
+
+        if data.len() < NONCE_LEN {
+            return Err(Error::from("invalid encrypted token".to_string()));
+        }
+
+        let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+
+        let plaintext = self
+            .cipher
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: associated.as_bytes(),
+                },
+            )
+            .map_err(|_| Error::from("failed to decrypt token".to_string()))?;
+
+        String::from_utf8(plaintext).map_err(|e| Error::from(e.to_string()))
+    }
+}
+
+/// Generates a URL safe random token from `len` random bytes.
+pub fn random_token(len: usize) -> String {
+    let mut bytes = vec![0u8; len];
+    rand::thread_rng().fill_bytes(&mut bytes);
+
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+/// Hashes a bearer secret for storage, so a database leak does not leak
+/// usable credentials.
+pub fn hash_token(token: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(token.as_bytes()))
+}
+
+/// Derives the `S256` PKCE code challenge for a code verifier.
+pub fn pkce_challenge(verifier: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=";
+
+    #[test]
+    fn round_trips() {
+        let cipher = TokenCipher::new(KEY).unwrap();
+
+        let encrypted = cipher.encrypt("discord token", "1").unwrap();
+        assert_ne!(encrypted, "discord token");
+        assert_eq!(cipher.decrypt(&encrypted, "1").unwrap(), "discord token");
+
+        // a fresh nonce every time
+        assert_ne!(cipher.encrypt("discord token", "1").unwrap(), encrypted);
+    }
+
+    #[test]
+    fn is_bound_to_the_associated_value() {
+        let cipher = TokenCipher::new(KEY).unwrap();
+
+        let encrypted = cipher.encrypt("discord token", "1").unwrap();
+        assert!(cipher.decrypt(&encrypted, "2").is_err());
+    }
+
+    #[test]
+    fn rejects_other_keys() {
+        let encrypted = TokenCipher::new(KEY)
+            .unwrap()
+            .encrypt("discord token", "1")
+            .unwrap();
+        let other = TokenCipher::new(&STANDARD.encode([1u8; 32])).unwrap();
+
+        assert!(other.decrypt(&encrypted, "1").is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_input() {
+        let cipher = TokenCipher::new(KEY).unwrap();
+        let encrypted = cipher.encrypt("discord token", "1").unwrap();
+        let bytes = STANDARD.decode(&encrypted).unwrap();
+
+        let mut tampered = bytes.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+
+        for input in [
+            String::new(),
+            "not base64!".to_string(),
+            STANDARD.encode(&bytes[..NONCE_LEN - 1]),
+            STANDARD.encode(&bytes[..NONCE_LEN]),
+            STANDARD.encode(&bytes[..bytes.len() - 1]),
+            STANDARD.encode(tampered),
+            STANDARD.encode([0u8; 64]),
+        ] {
+            assert!(cipher.decrypt(&input, "1").is_err(), "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn rejects_bad_keys() {
+        assert!(TokenCipher::new("not base64!").is_err());
+        assert!(TokenCipher::new(&STANDARD.encode([0u8; 16])).is_err());
+    }
+}
@@ -1,7 +1,14 @@
-use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use reqwest::header::HeaderMap;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use serde_with::skip_serializing_none;
 
-use crate::{errors::Error, Api};
+use crate::{crypto, errors::Error, oauth::AccessTokenResponse, ApiConfig};
 
 #[skip_serializing_none]
 #[derive(Debug, Serialize, Deserialize)]
@@ -13,17 +20,603 @@ pub struct DiscordUser {
     pub avatar: Option<String>,
 }
 
-impl Api {
-    pub async fn discord_token_to_user(&self, token: &str) -> Result<DiscordUser, Error> {
+#[skip_serializing_none]
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DiscordGuild {
+    pub id: String,
+    pub name: String,
+    pub icon: Option<String>,
+    pub owner_id: String,
+}
+
+/// A guild as returned by `/users/@me/guilds`, seen from the user's side.
+#[skip_serializing_none]
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DiscordPartialGuild {
+    pub id: String,
+    pub name: String,
+    pub icon: Option<String>,
+    pub owner: bool,
+    pub permissions: String,
+}
+
+/// A guild channel. `kind` is the Discord channel type, e.g. 0 for text and 4
+/// for categories.
+#[skip_serializing_none]
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DiscordChannel {
+    pub id: String,
+    pub name: Option<String>,
+    #[serde(rename = "type")]
+    pub kind: u8,
+    pub position: Option<i64>,
+    pub parent_id: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DiscordRole {
+    pub id: String,
+    pub name: String,
+    pub position: i64,
+    pub color: u32,
+    pub managed: bool,
+}
+
+/// The parts of a guild member the API uses.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DiscordMember {
+    pub roles: Vec<String>,
+}
+
+#[skip_serializing_none]
+#[derive(Debug, Default, Serialize)]
+pub struct DiscordEmbed {
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub color: Option<u32>,
+    pub fields: Vec<DiscordEmbedField>,
+    pub timestamp: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DiscordEmbedField {
+    pub name: String,
+    pub value: String,
+    pub inline: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DiscordMessage {
+    pub embeds: Vec<DiscordEmbed>,
+}
+
+/// The OAuth scope that lets a user token read its own guild memberships.
+pub const MEMBERS_READ_SCOPE: &str = "guilds.members.read";
+
+/// The Discord API the client talks to by default.
+pub const DISCORD_API_URL: &str = "https://discord.com/api";
+
+/// How long a request may wait on rate limits before it is given up on and
+/// the caller is asked to retry later.
+const RATE_LIMIT_BUDGET: Duration = Duration::from_secs(5);
+
+/// Routes include user and guild IDs, so buckets that have reset are swept
+/// once this many are tracked instead of waiting for their route to come up
+/// again.
+const BUCKET_SWEEP_THRESHOLD: usize = 1_000;
+
+struct Bucket {
+    remaining: u64,
+    reset_at: Instant,
+}
+
+/// What Discord told us about its rate limits so far. Buckets are tracked per
+/// route, and per token for routes that are limited per user.
+#[derive(Default)]
+struct RateLimits {
+    buckets: HashMap<String, Bucket>,
+    global_until: Option<Instant>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RateLimitResponse {
+    retry_after: f64,
+    #[serde(default)]
+    global: bool,
+}
+
+impl RateLimits {
+    /// Takes a request from the bucket of `route`, returning how long to wait
+    /// first if it or the global limit is exhausted.
+    fn acquire(&mut self, route: &str, now: Instant) -> Duration {
+        if let Some(until) = self.global_until {
+            if until > now {
+                return until - now;
+            }
+            self.global_until = None;
+        }
+
+        match self.buckets.get_mut(route) {
+            Some(bucket) if bucket.reset_at <= now => {
+                self.buckets.remove(route);
+                Duration::ZERO
+            }
+            Some(bucket) if bucket.remaining == 0 => bucket.reset_at - now,
+            Some(bucket) => {
+                bucket.remaining -= 1;
+                Duration::ZERO
+            }
+            None => Duration::ZERO,
+        }
+    }
+
+    fn insert(&mut self, route: &str, bucket: Bucket, now: Instant) {
+        if self.buckets.len() >= BUCKET_SWEEP_THRESHOLD {
+            self.buckets.retain(|_, bucket| bucket.reset_at > now);
+        }
+
+        self.buckets.insert(route.to_string(), bucket);
+    }
+
+    fn update(&mut self, route: &str, headers: &HeaderMap, now: Instant) {
+        let header = |name: &str| {
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.parse::<f64>().ok())
+        };
+
+        if let (Some(remaining), Some(reset_after)) = (
+            header("X-RateLimit-Remaining"),
+            header("X-RateLimit-Reset-After"),
+        ) {
+            self.insert(
+                route,
+                Bucket {
+                    remaining: remaining as u64,
+                    reset_at: now + Duration::from_secs_f64(reset_after),
+                },
+                now,
+            );
+        }
+    }
+
+    fn limited(&mut self, route: &str, retry_after: Duration, global: bool, now: Instant) {
+        if global {
+            self.global_until = Some(now + retry_after);
+        } else {
+            self.insert(
+                route,
+                Bucket {
+                    remaining: 0,
+                    reset_at: now + retry_after,
+                },
+                now,
+            );
+        }
+    }
+}
+
+/// Rate limits of user routes apply per token, without keeping the token
+/// itself around.
+fn token_route(route: &str, token: &str) -> String {
+    format!("{} {}", route, crypto::hash_token(token))
+}
+
+/// Returns the ID and token of a Discord webhook URL, such as
+/// `https://discord.com/api/webhooks/{id}/{token}`.
+pub fn parse_webhook_url(webhook_url: &str) -> Option<(String, String)> {
+    let url = url::Url::parse(webhook_url).ok()?;
+    let segments: Vec<&str> = url.path_segments()?.collect();
+
+    match segments.iter().position(|s| *s == "webhooks") {
+        Some(i)
+            if segments.len() >= i + 3
+                && !segments[i + 1].is_empty()
+                && !segments[i + 2].is_empty() =>
+        {
+            Some((segments[i + 1].to_string(), segments[i + 2].to_string()))
+        }
+        _ => None,
+    }
+}
+
+/// Talks to the Discord API for the OAuth flow, on behalf of users and as the
+/// bot. The base URL is configurable through `DISCORD_API_URL`, so it can be
+/// pointed at a proxy, or at [`crate::fake_discord`] to run offline.
+pub struct DiscordClient {
+    http: reqwest::Client,
+    base_url: String,
+    client_id: String,
+    client_secret: String,
+    bot_token: Option<String>,
+    rate_limits: Mutex<RateLimits>,
+}
+
+impl DiscordClient {
+    pub fn new(config: &ApiConfig) -> Self {
+        Self {
+            http: reqwest::Client::new(),
+            base_url: config.discord_api_url.trim_end_matches('/').to_string(),
+            client_id: config.discord_client_id.clone(),
+            client_secret: config.discord_client_secret.clone(),
+            bot_token: config.discord_bot_token.clone(),
+            rate_limits: Mutex::new(RateLimits::default()),
+        }
+    }
+
+    /// Sends a request once the rate limits of `route` allow it, retrying on
+    /// 429s. Fails with a rate limit error when that would take longer than
+    /// [`RATE_LIMIT_BUDGET`].
+    async fn send(
+        &self,
+        route: &str,
+        req: reqwest::RequestBuilder,
+    ) -> Result<reqwest::Response, Error> {
+        let deadline = Instant::now() + RATE_LIMIT_BUDGET;
+
+        loop {
+            let wait = self
+                .rate_limits
+                .lock()
+                .unwrap()
+                .acquire(route, Instant::now());
+
+            if !wait.is_zero() {
+                if Instant::now() + wait > deadline {
+                    return Err(Error::rate_limited(wait));
+                }
+
+                tokio::time::sleep(wait).await;
+                continue;
+            }
+
+            let attempt = match req.try_clone() {
+                Some(attempt) => attempt,
+                None => return Err(Error::from("request can not be retried".to_string())),
+            };
+
+            let resp = attempt.send().await?;
+            let now = Instant::now();
+
+            self.rate_limits
+                .lock()
+                .unwrap()
+                .update(route, resp.headers(), now);
+
+            if resp.status() != reqwest::StatusCode::TOO_MANY_REQUESTS {
+                return Ok(resp);
+            }
+
+            let global_header = resp.headers().contains_key("X-RateLimit-Global");
+            let retry_header = resp
+                .headers()
+                .get("Retry-After")
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.parse::<f64>().ok());
+
+            let (retry_after, global) = match resp.json::<RateLimitResponse>().await {
+                Ok(limit) => (limit.retry_after, limit.global || global_header),
+                Err(_) => (retry_header.unwrap_or(1.0), global_header),
+            };
+
+            tracing::warn!(
+                "discord rate limited {} for {}s (global: {})",
+                route,
+                retry_after,
+                global
+            );
+
+            self.rate_limits.lock().unwrap().limited(
+                route,
+                Duration::from_secs_f64(retry_after),
+                global,
+                now,
+            );
+        }
+    }
+
+    pub fn has_bot_token(&self) -> bool {
+        self.bot_token.is_some()
+    }
+
+    /// The authorization screen a login redirects the user to.
+    pub fn authorize_url(&self, redirect_uri: &str, state: &str, code_challenge: &str) -> String {
+        format!(
+            "{}/oauth2/authorize?client_id={}&redirect_uri={}&response_type=code&scope=identify%20guilds%20guilds.members.read&state={}&code_challenge={}&code_challenge_method=S256",
+            self.base_url,
+            self.client_id,
+            url::form_urlencoded::byte_serialize(redirect_uri.as_bytes()).collect::<String>(),
+            state,
+            code_challenge,
+        )
+    }
+
+    pub async fn exchange_code(
+        &self,
+        code: &str,
+        redirect_uri: &str,
+        code_verifier: &str,
+    ) -> Result<AccessTokenResponse, Error> {
+        let req = self
+            .http
+            .post(format!("{}/oauth2/token", self.base_url))
+            .basic_auth(&self.client_id, Some(&self.client_secret))
+            .form(&[
+                ("grant_type", "authorization_code"),
+                ("code", code),
+                ("redirect_uri", redirect_uri),
+                ("code_verifier", code_verifier),
+            ]);
+
+        let resp = self.send("POST /oauth2/token", req).await?;
+
+        let resp = resp.json::<AccessTokenResponse>().await?;
+
+        Ok(resp)
+    }
+
+    /// Exchanges a refresh token for a new token pair. Returns `None` when
+    /// Discord rejects the grant, e.g. because the user deauthorized the app.
+    pub async fn refresh_token(
+        &self,
+        refresh_token: &str,
+    ) -> Result<Option<AccessTokenResponse>, Error> {
+        let req = self
+            .http
+            .post(format!("{}/oauth2/token", self.base_url))
+            .basic_auth(&self.client_id, Some(&self.client_secret))
+            .form(&[
+                ("grant_type", "refresh_token"),
+                ("refresh_token", refresh_token),
+            ]);
+
+        let resp = self.send("POST /oauth2/token", req).await?;
+
+        if resp.status() == reqwest::StatusCode::BAD_REQUEST
+            || resp.status() == reqwest::StatusCode::UNAUTHORIZED
+        {
+            return Ok(None);
+        }
+
+        let resp = resp
+            .error_for_status()?
+            .json::<AccessTokenResponse>()
+            .await?;
+
+        Ok(Some(resp))
+    }
+
+    pub async fn current_user(&self, token: &str) -> Result<DiscordUser, Error> {
+        let req = self
+            .http
+            .get(format!("{}/users/@me", self.base_url))
+            .header("Authorization", format!("Bearer {}", token));
+
         let resp = self
-            .client
-            .get("https://discord.com/api/users/@me")
-            .header("Authorization", format!("Bearer {}", token))
-            .send()
+            .send(&token_route("GET /users/@me", token), req)
             .await?;
 
         let resp = resp.json::<DiscordUser>().await?;
 
         Ok(resp)
     }
+
+    pub async fn user_guilds(&self, token: &str) -> Result<Vec<DiscordPartialGuild>, Error> {
+        let req = self
+            .http
+            .get(format!("{}/users/@me/guilds", self.base_url))
+            .header("Authorization", format!("Bearer {}", token));
+
+        let resp = self
+            .send(&token_route("GET /users/@me/guilds", token), req)
+            .await?;
+
+        let resp = resp
+            .error_for_status()?
+            .json::<Vec<DiscordPartialGuild>>()
+            .await?;
+
+        Ok(resp)
+    }
+
+    /// Fetches the member of the token's user in `guild_id`, which needs the
+    /// `guilds.members.read` scope. Returns `None` when they are not in it.
+    pub async fn user_member(
+        &self,
+        token: &str,
+        guild_id: &str,
+    ) -> Result<Option<DiscordMember>, Error> {
+        let req = self
+            .http
+            .get(format!(
+                "{}/users/@me/guilds/{}/member",
+                self.base_url, guild_id
+            ))
+            .header("Authorization", format!("Bearer {}", token));
+
+        let route = format!("GET /users/@me/guilds/{}/member", guild_id);
+        let resp = self.send(&token_route(&route, token), req).await?;
+
+        if resp.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+
+        let resp = resp.error_for_status()?.json::<DiscordMember>().await?;
+
+        Ok(Some(resp))
+    }
+
+    /// Fetches `path` through the bot token. Returns `None` when it does not
+    /// exist or the bot has no access to it, e.g. because it is not a member
+    /// of the guild.
+    async fn bot_get<T: DeserializeOwned>(&self, path: &str) -> Result<Option<T>, Error> {
+        let token = match &self.bot_token {
+            Some(token) => token,
+            None => return Err(Error::from("DISCORD_BOT_TOKEN is not set".to_string())),
+        };
+
+        let req = self
+            .http
+            .get(format!("{}{}", self.base_url, path))
+            .header("Authorization", format!("Bot {}", token));
+
+        let resp = self.send(&format!("GET {}", path), req).await?;
+
+        if resp.status() == reqwest::StatusCode::NOT_FOUND
+            || resp.status() == reqwest::StatusCode::FORBIDDEN
+        {
+            return Ok(None);
+        }
+
+        let resp = resp.error_for_status()?.json::<T>().await?;
+
+        Ok(Some(resp))
+    }
+
+    /// Posts a message to a channel as the bot.
+    pub async fn bot_create_message(
+        &self,
+        channel_id: &str,
+        message: &DiscordMessage,
+    ) -> Result<(), Error> {
+        let token = match &self.bot_token {
+            Some(token) => token,
+            None => return Err(Error::from("DISCORD_BOT_TOKEN is not set".to_string())),
+        };
+
+        let req = self
+            .http
+            .post(format!(
+                "{}/channels/{}/messages",
+                self.base_url, channel_id
+            ))
+            .header("Authorization", format!("Bot {}", token))
+            .json(message);
+
+        self.send(&format!("POST /channels/{}/messages", channel_id), req)
+            .await?
+            .error_for_status()?;
+
+        Ok(())
+    }
+
+    /// Posts a message through a webhook URL. Only the webhook ID and token
+    /// are taken from the URL, the request always goes to the Discord API,
+    /// so a stored URL can not point the API at arbitrary hosts.
+    pub async fn execute_webhook(
+        &self,
+        webhook_url: &str,
+        message: &DiscordMessage,
+    ) -> Result<(), Error> {
+        let (id, token) = match parse_webhook_url(webhook_url) {
+            Some(webhook) => webhook,
+            None => return Err(Error::from("invalid webhook url".to_string())),
+        };
+
+        let req = self
+            .http
+            .post(format!("{}/webhooks/{}/{}", self.base_url, id, token))
+            .json(message);
+
+        self.send(&format!("POST /webhooks/{}", id), req)
+            .await?
+            .error_for_status()?;
+
+        Ok(())
+    }
+
+    pub async fn bot_guild(&self, guild_id: &str) -> Result<Option<DiscordGuild>, Error> {
+        self.bot_get(&format!("/guilds/{}", guild_id)).await
+    }
+
+    pub async fn bot_channels(&self, guild_id: &str) -> Result<Option<Vec<DiscordChannel>>, Error> {
+        self.bot_get(&format!("/guilds/{}/channels", guild_id))
+            .await
+    }
+
+    pub async fn bot_member(
+        &self,
+        guild_id: &str,
+        user_id: &str,
+    ) -> Result<Option<DiscordMember>, Error> {
+        self.bot_get(&format!("/guilds/{}/members/{}", guild_id, user_id))
+            .await
+    }
+
+    pub async fn bot_roles(&self, guild_id: &str) -> Result<Option<Vec<DiscordRole>>, Error> {
+        self.bot_get(&format!("/guilds/{}/roles", guild_id)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(remaining: u64, reset_after: f64) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("X-RateLimit-Remaining", remaining.into());
+        headers.insert(
+            "X-RateLimit-Reset-After",
+            reset_after.to_string().parse().unwrap(),
+        );
+        headers
+    }
+
+    #[test]
+    fn waits_for_exhausted_buckets() {
+        let mut limits = RateLimits::default();
+        let now = Instant::now();
+
+        limits.update("GET /users/@me", &headers(1, 2.0), now);
+        assert_eq!(limits.acquire("GET /users/@me", now), Duration::ZERO);
+        assert_eq!(
+            limits.acquire("GET /users/@me", now),
+            Duration::from_secs(2)
+        );
+        assert_eq!(
+            limits.acquire("GET /users/@me", now + Duration::from_secs(2)),
+            Duration::ZERO
+        );
+    }
+
+    #[test]
+    fn sweeps_reset_buckets() {
+        let mut limits = RateLimits::default();
+        let now = Instant::now();
+
+        for i in 0..BUCKET_SWEEP_THRESHOLD {
+            limits.update(&format!("GET /guilds/{}", i), &headers(4, 1.0), now);
+        }
+        limits.limited("GET /users/@me", Duration::from_secs(60), false, now);
+        assert_eq!(limits.buckets.len(), BUCKET_SWEEP_THRESHOLD + 1);
+
+        let later = now + Duration::from_secs(2);
+        limits.update("GET /guilds/new", &headers(4, 1.0), later);
+
+        // only the bucket that is still limited and the new one are left
+        assert_eq!(limits.buckets.len(), 2);
+        assert!(limits.acquire("GET /users/@me", later) > Duration::ZERO);
+    }
+
+    #[test]
+    fn parses_webhook_urls() {
+        assert_eq!(
+            parse_webhook_url("https://discord.com/api/webhooks/123/abc-def"),
+            Some(("123".to_string(), "abc-def".to_string()))
+        );
+        assert_eq!(
+            parse_webhook_url("https://discord.com/api/v10/webhooks/123/abc?wait=true"),
+            Some(("123".to_string(), "abc".to_string()))
+        );
+
+        for url in [
+            "not a url",
+            "https://discord.com/api/webhooks/123",
+            "https://discord.com/api/webhooks/123/",
+            "https://discord.com/api/channels/123/abc",
+        ] {
+            assert_eq!(parse_webhook_url(url), None, "{}", url);
+        }
+    }
 }
@@ -1,109 +1,332 @@
+use futures_util::{stream, StreamExt, TryStreamExt};
 use hyper::{Body, Request, Response};
+use serde::Serialize;
 
 use std::collections::HashMap;
 
 use crate::{
-    structs::{Appeal, Config},
+    auth::Principal,
+    cache, crypto, discord,
+    notifications::AppealEvent,
+    permissions::{
+        self, APPEALS_REVIEW, CONFIG_DELETE, CONFIG_READ, CONFIG_WRITE, PERMISSIONS_WRITE,
+    },
+    references::{self, ConfigWriteResponse},
+    structs::{Appeal, AppealStatus, AppealUpdate, AppealWebhook, AppealWebhookUpdate, Config},
     Api,
 };
 
+/// How many guilds of the guild list resolve their permissions at once.
+const MEMBER_LOOKUP_CONCURRENCY: usize = 4;
+
+#[derive(Debug, Serialize)]
+pub struct GuildListEntry {
+    pub id: String,
+    pub name: String,
+    pub icon: Option<String>,
+    pub can_manage: bool,
+}
+
+/// What is shown of a guild's appeals webhook, leaving out its token.
+#[derive(Debug, Serialize)]
+pub struct AppealWebhookInfo {
+    pub webhook_id: String,
+}
+
 impl Api {
     pub async fn get_guild_list(
         &self,
-        _req: Request<Body>,
+        req: Request<Body>,
+        user_id: &str,
     ) -> Result<Response<Body>, hyper::Error> {
-        return Ok(Response::builder()
-            .status(501)
-            .body(Body::from("not implemented"))
-            .unwrap());
+        // `refresh=true` skips the cached guild list, e.g. after joining a guild
+        let refresh = url::form_urlencoded::parse(req.uri().query().unwrap_or("").as_bytes())
+            .any(|(key, value)| key == "refresh" && value == "true");
+
+        if refresh {
+            self.cache
+                .invalidate(&cache::user_guilds_key(user_id))
+                .await;
+        }
+
+        let user_guilds = match self.user_guilds(user_id).await {
+            Ok(Some(guilds)) => guilds,
+            Ok(None) => return self.not_authorized("discord session expired").await,
+            Err(e) => {
+                tracing::error!("error getting user guilds: {}", e);
+                return self.discord_error(&e).await;
+            }
+        };
+
+        let ids: Vec<String> = user_guilds.iter().map(|guild| guild.id.clone()).collect();
+
+        let mut configs: HashMap<String, Config> = match self.db.get_guilds(&ids).await {
+            Ok(guilds) => guilds
+                .into_iter()
+                .map(|guild| (guild.guild_id, guild.config))
+                .collect(),
+            Err(e) => {
+                tracing::error!("error getting guilds: {}", e);
+                return self.internal_server_error().await;
+            }
+        };
+
+        let configured = user_guilds
+            .into_iter()
+            .filter_map(|guild| configs.remove(&guild.id).map(|config| (guild, config)));
+
+        // a cold cache can mean a Discord lookup per guild, so a few run at once
+        let guilds: Result<Vec<GuildListEntry>, _> = stream::iter(configured)
+            .map(|(guild, config)| async move {
+                let can_manage = self
+                    .member_has_permission(&guild.id, user_id, &config, CONFIG_WRITE)
+                    .await?;
+
+                Ok(GuildListEntry {
+                    id: guild.id,
+                    name: guild.name,
+                    icon: guild.icon,
+                    can_manage,
+                })
+            })
+            .buffered(MEMBER_LOOKUP_CONCURRENCY)
+            .try_collect()
+            .await;
+
+        match guilds {
+            Ok(guilds) => self.json_response(req, guilds).await,
+            Err(e) => {
+                tracing::error!("error resolving permissions: {}", e);
+                self.discord_error(&e).await
+            }
+        }
     }
 
     pub async fn get_guild(
         &self,
         req: Request<Body>,
         guild_id: &str,
+        principal: &Principal,
     ) -> Result<Response<Body>, hyper::Error> {
+        if let Some(resp) = self
+            .authorize_guild(principal, guild_id, CONFIG_READ)
+            .await?
+        {
+            return Ok(resp);
+        }
+
         let guild = match self.db.get_guild(guild_id).await {
             Ok(guild) => match guild {
                 Some(guild) => guild,
                 None => {
-                    return Ok(self.not_found().await?);
+                    return self.not_found().await;
                 }
             },
             Err(e) => {
                 tracing::error!("error getting guild: {}", e);
-                return Ok(self.internal_server_error().await?);
+                return self.internal_server_error().await;
             }
         };
 
         self.json_response(req, guild).await
     }
 
-    pub async fn post_guild(
+    /// Lists the channels of a guild as the bot sees them, so config editors
+    /// can offer names instead of raw IDs.
+    pub async fn get_guild_channels(
         &self,
         req: Request<Body>,
         guild_id: &str,
+        principal: &Principal,
     ) -> Result<Response<Body>, hyper::Error> {
-        match self.db.guild_exists(guild_id).await {
-            Ok(exists) => {
-                if exists {
-                    let params = match self.parse_query(&req).await?.0 {
-                        Some(params) => params,
-                        None => {
-                            return Ok(Response::builder()
-                                .status(409)
-                                .body(Body::from("Guild already exists"))
-                                .unwrap());
-                        }
-                    };
-
-                    let overwrite = params.get("overwrite").map(|v| v == "true").unwrap_or(false);
-
-                    if overwrite {
-                        match self.db.delete_guild(guild_id).await {
-                            Ok(_) => {}
-                            Err(e) => {
-                                tracing::error!("error deleting guild: {}", e);
-                                return Ok(self.internal_server_error().await?);
-                            }
-                        }
-                    } else {
-                        return Ok(Response::builder()
-                            .status(409)
-                            .body(Body::from("Guild already exists"))
-                            .unwrap());
-                    }
-                }
+        if let Some(resp) = self
+            .authorize_guild(principal, guild_id, CONFIG_READ)
+            .await?
+        {
+            return Ok(resp);
+        }
+
+        let mut channels = match self.guild_channels(guild_id).await {
+            Ok(Some(channels)) => channels,
+            Ok(None) => return self.not_found().await,
+            Err(e) => {
+                tracing::error!("error getting guild channels: {}", e);
+                return self.discord_error(&e).await;
             }
+        };
+
+        channels.sort_by_key(|channel| channel.position);
+
+        self.json_response(req, channels).await
+    }
+
+    pub async fn get_guild_roles(
+        &self,
+        req: Request<Body>,
+        guild_id: &str,
+        principal: &Principal,
+    ) -> Result<Response<Body>, hyper::Error> {
+        if let Some(resp) = self
+            .authorize_guild(principal, guild_id, CONFIG_READ)
+            .await?
+        {
+            return Ok(resp);
+        }
+
+        let mut roles = match self.guild_roles(guild_id).await {
+            Ok(Some(roles)) => roles,
+            Ok(None) => return self.not_found().await,
+            Err(e) => {
+                tracing::error!("error getting guild roles: {}", e);
+                return self.discord_error(&e).await;
+            }
+        };
+
+        // highest role first, like the Discord client shows them
+        roles.sort_by_key(|role| std::cmp::Reverse(role.position));
+
+        self.json_response(req, roles).await
+    }
+
+    pub async fn post_guild(
+        &self,
+        req: Request<Body>,
+        guild_id: &str,
+        principal: &Principal,
+    ) -> Result<Response<Body>, hyper::Error> {
+        let exists = match self.db.guild_exists(guild_id).await {
+            Ok(exists) => exists,
             Err(e) => {
                 tracing::error!("error checking if guild exists: {}", e);
-                return Ok(self.internal_server_error().await?);
+                return self.internal_server_error().await;
+            }
+        };
+
+        if exists {
+            if let Some(resp) = self
+                .authorize_guild(principal, guild_id, CONFIG_WRITE)
+                .await?
+            {
+                return Ok(resp);
+            }
+
+            let params = match self.parse_query(&req).await?.0 {
+                Some(params) => params,
+                None => {
+                    return Ok(Response::builder()
+                        .status(409)
+                        .body(Body::from("Guild already exists"))
+                        .unwrap());
+                }
+            };
+
+            let overwrite = params
+                .get("overwrite")
+                .map(|v| v == "true")
+                .unwrap_or(false);
+
+            if !overwrite {
+                return Ok(Response::builder()
+                    .status(409)
+                    .body(Body::from("Guild already exists"))
+                    .unwrap());
+            }
+
+            if let Some(resp) = self
+                .authorize_guild(principal, guild_id, CONFIG_DELETE)
+                .await?
+            {
+                return Ok(resp);
+            }
+        } else {
+            let user_id = match principal.user_id() {
+                Some(user_id) => user_id,
+                None => return self.forbidden("api keys cannot bootstrap a guild").await,
+            };
+
+            if let Some(resp) = self.authorize_bootstrap(guild_id, user_id).await? {
+                return Ok(resp);
             }
         }
 
-        let body_bytes = match hyper::body::to_bytes(req.into_body()).await {
+        let strict = references::strict_requested(&req);
+
+        let (parts, body) = req.into_parts();
+        let body_bytes = match hyper::body::to_bytes(body).await {
             Ok(bytes) => bytes,
             Err(e) => {
                 tracing::error!("error reading body: {}", e);
-                return Ok(self.internal_server_error().await?);
+                return self.internal_server_error().await;
             }
         };
 
-        let config: Config = match serde_json::from_slice(&body_bytes) {
+        let mut config: Config = match serde_json::from_slice(&body_bytes) {
             Ok(config) => config,
             Err(e) => {
                 tracing::error!("error parsing body: {}", e);
-                return Ok(self.bad_request("invalid body").await?);
+                return self.bad_request("invalid body").await;
             }
         };
 
-        match self.db.create_guild(guild_id, config).await {
+        // authorized before the config is validated or its references are
+        // looked up, so neither can be used without the permission
+        if exists {
+            let old = match self.db.get_guild(guild_id).await {
+                Ok(old) => old,
+                Err(e) => {
+                    tracing::error!("error getting guild: {}", e);
+                    return self.internal_server_error().await;
+                }
+            };
+
+            if permissions::permissions_changed(old.as_ref(), &config) {
+                if let Some(resp) = self
+                    .authorize_guild(principal, guild_id, PERMISSIONS_WRITE)
+                    .await?
+                {
+                    return Ok(resp);
+                }
+            }
+        }
+
+        if let Err(e) = permissions::validate_config(&config) {
+            return self.bad_request(&e).await;
+        }
+
+        let dangling_references = match self
+            .check_write_references(guild_id, &config, strict)
+            .await?
+        {
+            (Some(resp), _) => return Ok(resp),
+            (None, dangling) => dangling,
+        };
+
+        if exists {
+            if let Err(e) = self.db.delete_guild(guild_id).await {
+                tracing::error!("error deleting guild: {}", e);
+                return self.internal_server_error().await;
+            }
+        } else if let Some(user_id) = principal.user_id() {
+            permissions::grant_bootstrap(&mut config, user_id);
+        }
+
+        match self.db.create_guild(guild_id, config.clone()).await {
             Ok(_) => {
-                return Ok(Response::builder().status(201).body(Body::empty()).unwrap());
+                let mut resp = self
+                    .json_response(
+                        Request::from_parts(parts, Body::empty()),
+                        ConfigWriteResponse {
+                            config,
+                            dangling_references,
+                        },
+                    )
+                    .await?;
+                *resp.status_mut() = hyper::StatusCode::CREATED;
+                Ok(resp)
             }
             Err(e) => {
                 tracing::error!("error creating guild: {}", e);
-                return Ok(self.internal_server_error().await?);
+                return self.internal_server_error().await;
             }
         }
     }
@@ -112,13 +335,23 @@ impl Api {
         &self,
         req: Request<Body>,
         guild_id: &str,
+        principal: &Principal,
     ) -> Result<Response<Body>, hyper::Error> {
+        if let Some(resp) = self
+            .authorize_guild(principal, guild_id, CONFIG_WRITE)
+            .await?
+        {
+            return Ok(resp);
+        }
+
+        let strict = references::strict_requested(&req);
+
         let (parts, body) = req.into_parts();
         let body_bytes = match hyper::body::to_bytes(body).await {
             Ok(bytes) => bytes,
             Err(e) => {
                 tracing::error!("error reading body: {}", e);
-                return Ok(self.internal_server_error().await?);
+                return self.internal_server_error().await;
             }
         };
 
@@ -126,36 +359,62 @@ impl Api {
             Ok(guild) => match guild {
                 Some(c) => c,
                 None => {
-                    return Ok(self.not_found().await?);
+                    return self.not_found().await;
                 }
             },
             Err(e) => {
                 tracing::error!("error getting guild: {}", e);
-                return Ok(self.internal_server_error().await?);
+                return self.internal_server_error().await;
             }
         };
 
         let config: Config = match serde_json::from_slice(&body_bytes) {
-            Ok(config) => old.merge_from(config),
+            Ok(config) => old.clone().merge_from(config),
             Err(e) => {
                 tracing::error!("error parsing config: {}", e);
-                return Ok(self.bad_request("invalid config").await?);
+                return self.bad_request("invalid config").await;
             }
         };
 
+        if permissions::permissions_changed(Some(&old), &config) {
+            if let Some(resp) = self
+                .authorize_guild(principal, guild_id, PERMISSIONS_WRITE)
+                .await?
+            {
+                return Ok(resp);
+            }
+        }
+
+        if let Err(e) = permissions::validate_config(&config) {
+            return self.bad_request(&e).await;
+        }
+
+        let dangling_references = match self
+            .check_write_references(guild_id, &config, strict)
+            .await?
+        {
+            (Some(resp), _) => return Ok(resp),
+            (None, dangling) => dangling,
+        };
 
         match self.db.update_guild(guild_id, config).await {
             Ok(guild) => {
                 if let Some(guild) = guild {
                     return self
-                        .json_response(Request::from_parts(parts, Body::empty()), guild.config)
+                        .json_response(
+                            Request::from_parts(parts, Body::empty()),
+                            ConfigWriteResponse {
+                                config: guild.config,
+                                dangling_references,
+                            },
+                        )
                         .await;
                 }
                 return self.not_found().await;
             }
             Err(e) => {
                 tracing::error!("error updating guild: {}", e);
-                return Ok(self.internal_server_error().await?);
+                return self.internal_server_error().await;
             }
         }
     }
@@ -164,23 +423,85 @@ impl Api {
         &self,
         _req: Request<Body>,
         guild_id: &str,
+        principal: &Principal,
     ) -> Result<Response<Body>, hyper::Error> {
-        match self.db.delete_guild(guild_id).await {
-            Ok(_) => {
-                return Ok(Response::builder().status(204).body(Body::empty()).unwrap());
-            }
+        if let Some(resp) = self
+            .authorize_guild(principal, guild_id, CONFIG_DELETE)
+            .await?
+        {
+            return Ok(resp);
+        }
+
+        if let Err(e) = self.db.delete_guild(guild_id).await {
+            tracing::error!("error deleting guild: {}", e);
+            return self.internal_server_error().await;
+        }
+
+        // only here, overwriting a config through post_guild keeps the webhook
+        match self.db.delete_appeal_webhook(guild_id).await {
+            Ok(_) => Ok(Response::builder().status(204).body(Body::empty()).unwrap()),
             Err(e) => {
-                tracing::error!("error deleting guild: {}", e);
-                return Ok(self.internal_server_error().await?);
+                tracing::error!("error deleting appeal webhook: {}", e);
+                self.internal_server_error().await
+            }
+        }
+    }
+
+    pub async fn get_guild_permissions(
+        &self,
+        req: Request<Body>,
+        guild_id: &str,
+        target_id: &str,
+        principal: &Principal,
+    ) -> Result<Response<Body>, hyper::Error> {
+        if principal.user_id() != Some(target_id) {
+            if let Some(resp) = self
+                .authorize_guild(principal, guild_id, CONFIG_READ)
+                .await?
+            {
+                return Ok(resp);
             }
         }
+
+        let config = match self.db.get_guild(guild_id).await {
+            Ok(Some(config)) => config,
+            Ok(None) => return self.not_found().await,
+            Err(e) => {
+                tracing::error!("error getting guild: {}", e);
+                return self.internal_server_error().await;
+            }
+        };
+
+        let roles = if principal.user_id() == Some(target_id) {
+            self.member_roles(guild_id, target_id).await
+        } else {
+            self.other_member_roles(guild_id, target_id).await
+        };
+
+        let roles = match roles {
+            Ok(roles) => roles,
+            Err(e) => {
+                tracing::error!("error getting member roles: {}", e);
+                return self.discord_error(&e).await;
+            }
+        };
+
+        let extra = permissions::requested_permissions(&req);
+
+        let set = permissions::resolve(&config, target_id, &roles);
+        let explanation = set.explain(target_id, &roles, &extra);
+
+        self.json_response(req, explanation).await
     }
 
     pub async fn options_guild(&self, _req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
         Ok(Response::builder()
             .status(200)
             .header("Allow", "GET, POST, PATCH, DELETE, OPTIONS")
-            .header("Access-Control-Allow-Headers", "Content-Type")
+            .header(
+                "Access-Control-Allow-Headers",
+                "Content-Type, Authorization, X-CSRF-Token",
+            )
             .body(Body::empty())
             .unwrap())
     }
@@ -189,6 +510,7 @@ impl Api {
         &self,
         req: Request<Body>,
         id: &str,
+        principal: &Principal,
     ) -> Result<Response<Body>, hyper::Error> {
         let query_params = match req.uri().query() {
             Some(query) => {
@@ -214,22 +536,30 @@ impl Api {
 
         match id_type {
             "guild" => {
+                if let Some(resp) = self.authorize_guild(principal, id, APPEALS_REVIEW).await? {
+                    return Ok(resp);
+                }
+
                 let appeals = match self.db.get_appeals(Some(id), None).await {
                     Ok(appeals) => appeals,
                     Err(e) => {
                         tracing::error!("error getting appeals: {}", e);
-                        return Ok(self.internal_server_error().await?);
+                        return self.internal_server_error().await;
                     }
                 };
 
                 self.json_response(req, appeals).await
             }
             "user" => {
+                if principal.user_id() != Some(id) {
+                    return self.forbidden("cannot view another user's appeals").await;
+                }
+
                 let appeals = match self.db.get_appeals(None, Some(id)).await {
                     Ok(appeals) => appeals,
                     Err(e) => {
                         tracing::error!("error getting appeals: {}", e);
-                        return Ok(self.internal_server_error().await?);
+                        return self.internal_server_error().await;
                     }
                 };
 
@@ -245,35 +575,123 @@ impl Api {
         &self,
         req: Request<Body>,
         uuid: &str,
-        user_id: &str,
+        principal: &Principal,
     ) -> Result<Response<Body>, hyper::Error> {
         let (parts, body) = req.into_parts();
         let body_bytes = match hyper::body::to_bytes(body).await {
             Ok(bytes) => bytes,
             Err(e) => {
                 tracing::error!("error reading body: {}", e);
-                return Ok(self.internal_server_error().await?);
+                return self.internal_server_error().await;
             }
         };
 
-        let appeal: Appeal = match serde_json::from_slice(&body_bytes) {
-            Ok(appeal) => appeal,
+        let update: AppealUpdate = match serde_json::from_slice(&body_bytes) {
+            Ok(update) => update,
             Err(e) => {
-                tracing::error!("error parsing appeal: {}", e);
-                return Ok(self.bad_request("invalid appeal").await?);
+                tracing::error!("error parsing appeal update: {}", e);
+                return self.bad_request("invalid appeal update").await;
             }
         };
 
-        match self.db.update_appeal(uuid, user_id, appeal).await {
-            Ok(appeal) => {
-                let req = Request::from_parts(parts, Body::empty());
-                return self.json_response(req, appeal).await;
-            }
+        let decides = update.status.is_some() || update.status_reason.is_some();
+
+        if update.content.is_none() && !decides {
+            return self.bad_request("empty appeal update").await;
+        }
+
+        let appeal = match self.db.get_appeal(uuid).await {
+            Ok(Some(appeal)) => appeal,
+            Ok(None) => return self.not_found().await,
             Err(e) => {
-                tracing::error!("error updating guild: {}", e);
-                return Ok(self.internal_server_error().await?);
+                tracing::error!("error getting appeal: {}", e);
+                return self.internal_server_error().await;
+            }
+        };
+
+        let is_appellant = principal.user_id() == Some(appeal.user_id.as_str());
+
+        // every field is authorized before anything is written, so a
+        // rejected field never leaves the others half applied
+        if update.content.is_some() {
+            if !is_appellant {
+                return self
+                    .forbidden("only the appellant can edit an appeal")
+                    .await;
+            }
+
+            if appeal.status != AppealStatus::Pending {
+                return Ok(Response::builder()
+                    .status(409)
+                    .body(Body::from("Appeal has already been decided"))
+                    .unwrap());
+            }
+        }
+
+        if decides {
+            if is_appellant {
+                return self.forbidden("cannot decide your own appeal").await;
+            }
+
+            if let Some(resp) = self
+                .authorize_guild(principal, &appeal.guild_id, APPEALS_REVIEW)
+                .await?
+            {
+                return Ok(resp);
             }
         }
+
+        let mut updated = appeal;
+        let mut event = None;
+
+        if let Some(content) = update.content {
+            updated = match self
+                .db
+                .update_appeal_content(uuid, &updated.user_id, content)
+                .await
+            {
+                Ok(Some(appeal)) => appeal,
+                Ok(None) => {
+                    return Ok(Response::builder()
+                        .status(409)
+                        .body(Body::from("Appeal has already been decided"))
+                        .unwrap());
+                }
+                Err(e) => {
+                    tracing::error!("error updating appeal: {}", e);
+                    return self.internal_server_error().await;
+                }
+            };
+
+            event = Some(AppealEvent::Edited);
+        }
+
+        if decides {
+            let status = update.status.unwrap_or(updated.status);
+            let status_reason = update.status_reason.or(updated.status_reason);
+
+            updated = match self
+                .db
+                .update_appeal_status(uuid, status, status_reason)
+                .await
+            {
+                Ok(Some(appeal)) => appeal,
+                Ok(None) => return self.not_found().await,
+                Err(e) => {
+                    tracing::error!("error updating appeal: {}", e);
+                    return self.internal_server_error().await;
+                }
+            };
+
+            event = Some(AppealEvent::Decided);
+        }
+
+        if let Some(event) = event {
+            self.notify_appeal(&updated, event);
+        }
+
+        let req = Request::from_parts(parts, Body::empty());
+        self.json_response(req, updated).await
     }
 
     pub async fn create_appeal(
@@ -282,33 +700,45 @@ impl Api {
         guild_id: &str,
         user_id: &str,
     ) -> Result<Response<Body>, hyper::Error> {
-        let body_bytes = match hyper::body::to_bytes(req.into_body()).await {
+        let (parts, body) = req.into_parts();
+        let body_bytes = match hyper::body::to_bytes(body).await {
             Ok(bytes) => bytes,
             Err(e) => {
                 tracing::error!("error reading body: {}", e);
-                return Ok(self.internal_server_error().await?);
+                return self.internal_server_error().await;
             }
         };
 
-        let appeal: Appeal = match serde_json::from_slice(&body_bytes) {
+        let mut appeal: Appeal = match serde_json::from_slice(&body_bytes) {
             Ok(appeal) => appeal,
             Err(e) => {
                 tracing::error!("error parsing appeal: {}", e);
-                return Ok(self.bad_request("invalid appeal").await?);
+                return self.bad_request("invalid appeal").await;
             }
         };
 
+        // new appeals always start out undecided, whatever the body says
+        appeal.id = crypto::random_token(16);
+        appeal.status = AppealStatus::Pending;
+        appeal.status_reason = None;
+
         if appeal.guild_id != guild_id || appeal.user_id != user_id {
             return self.not_authorized("id mismatch").await;
         }
 
-        match self.db.create_appeal(appeal).await {
+        match self.db.create_appeal(appeal.clone()).await {
             Ok(_) => {
-                return Ok(Response::builder().status(201).body(Body::empty()).unwrap());
+                self.notify_appeal(&appeal, AppealEvent::Created);
+
+                let mut resp = self
+                    .json_response(Request::from_parts(parts, Body::empty()), appeal)
+                    .await?;
+                *resp.status_mut() = hyper::StatusCode::CREATED;
+                Ok(resp)
             }
             Err(e) => {
                 tracing::error!("error creating guild: {}", e);
-                return Ok(self.internal_server_error().await?);
+                return self.internal_server_error().await;
             }
         }
     }
@@ -324,11 +754,11 @@ impl Api {
                 if res.deleted_count == 0 {
                     return self.not_found().await;
                 }
-                return Ok(Response::builder().status(204).body(Body::empty()).unwrap());
+                Ok(Response::builder().status(204).body(Body::empty()).unwrap())
             }
             Err(e) => {
                 tracing::error!("error deleting guild: {}", e);
-                return Ok(self.internal_server_error().await?);
+                return self.internal_server_error().await;
             }
         }
     }
@@ -343,4 +773,116 @@ impl Api {
             .body(Body::empty())
             .unwrap())
     }
+
+    pub async fn get_appeal_webhook(
+        &self,
+        req: Request<Body>,
+        guild_id: &str,
+        principal: &Principal,
+    ) -> Result<Response<Body>, hyper::Error> {
+        if let Some(resp) = self
+            .authorize_guild(principal, guild_id, CONFIG_READ)
+            .await?
+        {
+            return Ok(resp);
+        }
+
+        match self.db.get_appeal_webhook(guild_id).await {
+            Ok(Some(webhook)) => {
+                self.json_response(
+                    req,
+                    AppealWebhookInfo {
+                        webhook_id: webhook.webhook_id,
+                    },
+                )
+                .await
+            }
+            Ok(None) => self.not_found().await,
+            Err(e) => {
+                tracing::error!("error getting appeal webhook: {}", e);
+                self.internal_server_error().await
+            }
+        }
+    }
+
+    /// Sets the webhook appeal notifications of a guild are posted through.
+    /// The URL is stored encrypted and never returned.
+    pub async fn put_appeal_webhook(
+        &self,
+        req: Request<Body>,
+        guild_id: &str,
+        principal: &Principal,
+    ) -> Result<Response<Body>, hyper::Error> {
+        if let Some(resp) = self
+            .authorize_guild(principal, guild_id, CONFIG_WRITE)
+            .await?
+        {
+            return Ok(resp);
+        }
+
+        let body_bytes = match hyper::body::to_bytes(req.into_body()).await {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                tracing::error!("error reading body: {}", e);
+                return self.internal_server_error().await;
+            }
+        };
+
+        let update: AppealWebhookUpdate = match serde_json::from_slice(&body_bytes) {
+            Ok(update) => update,
+            Err(e) => {
+                tracing::error!("error parsing appeal webhook: {}", e);
+                return self.bad_request("invalid appeal webhook").await;
+            }
+        };
+
+        let webhook_id = match discord::parse_webhook_url(&update.url) {
+            Some((webhook_id, _)) => webhook_id,
+            None => return self.bad_request("invalid webhook url").await,
+        };
+
+        let url = match self.cipher.encrypt(&update.url, guild_id) {
+            Ok(url) => url,
+            Err(e) => {
+                tracing::error!("error encrypting appeal webhook: {}", e);
+                return self.internal_server_error().await;
+            }
+        };
+
+        let webhook = AppealWebhook {
+            guild_id: guild_id.to_string(),
+            webhook_id,
+            url,
+        };
+
+        match self.db.set_appeal_webhook(webhook).await {
+            Ok(_) => Ok(Response::builder().status(204).body(Body::empty()).unwrap()),
+            Err(e) => {
+                tracing::error!("error setting appeal webhook: {}", e);
+                self.internal_server_error().await
+            }
+        }
+    }
+
+    pub async fn delete_appeal_webhook(
+        &self,
+        _req: Request<Body>,
+        guild_id: &str,
+        principal: &Principal,
+    ) -> Result<Response<Body>, hyper::Error> {
+        if let Some(resp) = self
+            .authorize_guild(principal, guild_id, CONFIG_WRITE)
+            .await?
+        {
+            return Ok(resp);
+        }
+
+        match self.db.delete_appeal_webhook(guild_id).await {
+            Ok(_) => Ok(Response::builder().status(204).body(Body::empty()).unwrap()),
+            Err(e) => {
+                tracing::error!("error deleting appeal webhook: {}", e);
+                self.internal_server_error().await
+            }
+        }
+    }
 }
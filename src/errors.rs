@@ -14,6 +14,8 @@ enum ErrorRepr {
     HyperError(#[from] hyper::Error),
     #[error("reqwest error: {0}")]
     ReqwestError(#[from] reqwest::Error),
+    #[error("discord rate limited, retry after {0:?}")]
+    RateLimited(std::time::Duration),
     #[error("unknown error: {0}")]
     Unknown(String),
 }
@@ -22,66 +24,65 @@ enum ErrorRepr {
 #[error(transparent)]
 pub struct Error(#[from] ErrorRepr);
 
+impl Error {
+    /// A Discord request that could not be made within the rate limit budget.
+    pub fn rate_limited(retry_after: std::time::Duration) -> Self {
+        Self(ErrorRepr::RateLimited(retry_after))
+    }
+
+    /// How long to wait before retrying, if this is a rate limit error.
+    pub fn retry_after(&self) -> Option<std::time::Duration> {
+        match self.0 {
+            ErrorRepr::RateLimited(retry_after) => Some(retry_after),
+            _ => None,
+        }
+    }
+}
+
 impl From<jsonwebtoken::errors::Error> for Error {
     fn from(e: jsonwebtoken::errors::Error) -> Self {
-        Self {
-            0: ErrorRepr::JwtError(e),
-        }
+        Self(ErrorRepr::JwtError(e))
     }
 }
 
 impl From<Box<dyn std::error::Error>> for Error {
     fn from(e: Box<dyn std::error::Error>) -> Self {
-        Self {
-            0: ErrorRepr::Unknown(e.to_string()),
-        }
+        Self(ErrorRepr::Unknown(e.to_string()))
     }
 }
 
 impl From<mongodb::error::Error> for Error {
     fn from(e: mongodb::error::Error) -> Self {
-        Self {
-            0: ErrorRepr::MongoError(e),
-        }
+        Self(ErrorRepr::MongoError(e))
     }
 }
 
 impl From<std::io::Error> for Error {
     fn from(e: std::io::Error) -> Self {
-        Self {
-            0: ErrorRepr::IOError(e),
-        }
+        Self(ErrorRepr::IOError(e))
     }
 }
 
 impl From<serde_json::Error> for Error {
     fn from(e: serde_json::Error) -> Self {
-        Self {
-            0: ErrorRepr::SerdeError(e),
-        }
+        Self(ErrorRepr::SerdeError(e))
     }
 }
 
 impl From<hyper::Error> for Error {
     fn from(value: hyper::Error) -> Self {
-        Self {
-            0: ErrorRepr::HyperError(value),
-        }
+        Self(ErrorRepr::HyperError(value))
     }
 }
 
 impl From<reqwest::Error> for Error {
     fn from(value: reqwest::Error) -> Self {
-        Self {
-            0: ErrorRepr::ReqwestError(value),
-        }
+        Self(ErrorRepr::ReqwestError(value))
     }
 }
 
 impl From<String> for Error {
     fn from(e: String) -> Self {
-        Self {
-            0: ErrorRepr::Unknown(e),
-        }
+        Self(ErrorRepr::Unknown(e))
     }
 }
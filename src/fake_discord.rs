@@ -0,0 +1,539 @@
+//! A stand-in for the parts of the Discord API the API uses, so the login →
+//! JWT → guild listing flow can run without reaching Discord. Started by
+//! setting `FAKE_DISCORD_PORT`, which also points `DISCORD_API_URL` at it.
+//! Only compiled into debug builds.
+//!
+//! The authorization screen approves every login straight away as
+//! [`FAKE_USER_ID`], who owns every fake guild. PKCE and the redirect URI are
+//! checked like Discord does. Messages and webhook executions are accepted
+//! and dropped.
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::{Arc, Mutex},
+};
+
+use hyper::{Body, Method, Request, Response};
+use serde_json::json;
+
+use crate::{crypto, ApiConfig};
+
+pub const FAKE_USER_ID: &str = "100000000000000001";
+
+const FAKE_GUILDS: [(&str, &str); 2] = [
+    ("200000000000000001", "Black Mesa Research Facility"),
+    ("200000000000000002", "Aperture Science"),
+];
+
+const FAKE_TOKEN_TTL: i64 = 7 * 24 * 60 * 60;
+
+struct PendingCode {
+    redirect_uri: String,
+    code_challenge: String,
+}
+
+#[derive(Default)]
+struct FakeState {
+    codes: HashMap<String, PendingCode>,
+    access_tokens: Vec<String>,
+    refresh_tokens: Vec<String>,
+}
+
+#[derive(Clone, Default)]
+pub struct FakeDiscord {
+    state: Arc<Mutex<FakeState>>,
+}
+
+/// Starts the fake if `FAKE_DISCORD_PORT` is set, pointing `config` at it
+/// and giving it a bot token the fake accepts.
+pub fn from_env(mut config: ApiConfig) -> ApiConfig {
+    if let Ok(port) = std::env::var("FAKE_DISCORD_PORT") {
+        let port: u16 = port.parse().expect("FAKE_DISCORD_PORT must be a port");
+        let addr = FakeDiscord::spawn(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port));
+
+        config.discord_api_url = format!("http://{}/api", addr);
+        config
+            .discord_bot_token
+            .get_or_insert_with(|| "fake".to_string());
+    }
+
+    config
+}
+
+impl FakeDiscord {
+    /// Serves the fake API on `addr` until the process exits, returning the
+    /// address it is bound to (`addr` may use port 0).
+    pub fn spawn(addr: SocketAddr) -> SocketAddr {
+        let fake = FakeDiscord::default();
+
+        let make_svc = hyper::service::make_service_fn(move |_| {
+            let fake = fake.clone();
+            async move {
+                Ok::<_, hyper::Error>(hyper::service::service_fn(move |req| {
+                    let fake = fake.clone();
+                    async move { fake.handle_request(req).await }
+                }))
+            }
+        });
+
+        let server = hyper::Server::bind(&addr).serve(make_svc);
+        let addr = server.local_addr();
+
+        tracing::warn!("Serving a fake Discord API on http://{}/api", addr);
+
+        tokio::spawn(async move {
+            if let Err(err) = server.await {
+                tracing::error!("fake Discord API stopped: {}", err);
+            }
+        });
+
+        addr
+    }
+
+    async fn handle_request(&self, req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
+        let uri = req.uri().clone();
+        let path_parts: Vec<&str> = uri.path().trim_end_matches('/').split('/').collect();
+
+        match (req.method(), &path_parts[1..]) {
+            (&Method::GET, ["api", "oauth2", "authorize"]) => Ok(self.authorize(&req)),
+            (&Method::POST, ["api", "oauth2", "token"]) => {
+                let body = hyper::body::to_bytes(req.into_body()).await?;
+                Ok(self.token(&body))
+            }
+            (&Method::GET, ["api", "users", "@me"]) => Ok(self.with_user(
+                &req,
+                json!({
+                    "id": FAKE_USER_ID,
+                    "username": "gordon",
+                    "discriminator": "0",
+                    "global_name": "Gordon Freeman",
+                    "avatar": null,
+                }),
+            )),
+            (&Method::GET, ["api", "users", "@me", "guilds"]) => Ok(self.with_user(
+                &req,
+                FAKE_GUILDS
+                    .iter()
+                    .map(|(id, name)| {
+                        json!({
+                            "id": id,
+                            "name": name,
+                            "icon": null,
+                            "owner": true,
+                            "permissions": "8",
+                        })
+                    })
+                    .collect(),
+            )),
+            (&Method::GET, ["api", "guilds", guild_id]) => Ok(self.bot_guild(&req, guild_id, None)),
+            (&Method::GET, ["api", "guilds", guild_id, resource]) => {
+                Ok(self.bot_guild(&req, guild_id, Some(resource)))
+            }
+            (&Method::GET, ["api", "users", "@me", "guilds", guild_id, "member"]) => {
+                if FAKE_GUILDS.iter().any(|(id, _)| id == guild_id) {
+                    Ok(self.with_user(&req, fake_member()))
+                } else {
+                    Ok(status(
+                        404,
+                        json!({ "message": "Unknown Guild", "code": 10004 }),
+                    ))
+                }
+            }
+            (&Method::GET, ["api", "guilds", guild_id, "members", user_id]) => {
+                let guild = self.bot_guild(&req, guild_id, None);
+
+                if guild.status() != hyper::StatusCode::OK {
+                    Ok(guild)
+                } else if *user_id == FAKE_USER_ID {
+                    Ok(status(200, fake_member()))
+                } else {
+                    Ok(status(
+                        404,
+                        json!({ "message": "Unknown Member", "code": 10007 }),
+                    ))
+                }
+            }
+            (&Method::POST, ["api", "channels", channel_id, "messages"]) => {
+                Ok(self.bot_message(&req, channel_id))
+            }
+            (&Method::POST, ["api", "webhooks", _, _]) => {
+                Ok(Response::builder().status(204).body(Body::empty()).unwrap())
+            }
+            _ => Ok(status(
+                404,
+                json!({ "message": "404: Not Found", "code": 0 }),
+            )),
+        }
+    }
+
+    /// Approves the login immediately and sends the user back with a code.
+    fn authorize(&self, req: &Request<Body>) -> Response<Body> {
+        let params = query(req.uri().query().unwrap_or(""));
+
+        let (redirect_uri, code_challenge) =
+            match (params.get("redirect_uri"), params.get("code_challenge")) {
+                (Some(redirect_uri), Some(code_challenge)) => (redirect_uri, code_challenge),
+                _ => return status(400, json!({ "error": "invalid_request" })),
+            };
+
+        let code = crypto::random_token(16);
+
+        self.state.lock().unwrap().codes.insert(
+            code.clone(),
+            PendingCode {
+                redirect_uri: redirect_uri.clone(),
+                code_challenge: code_challenge.clone(),
+            },
+        );
+
+        let query = url::form_urlencoded::Serializer::new(String::new())
+            .append_pair("code", &code)
+            .append_pair(
+                "state",
+                params.get("state").map(String::as_str).unwrap_or(""),
+            )
+            .finish();
+
+        Response::builder()
+            .status(302)
+            .header("Location", format!("{}?{}", redirect_uri, query))
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    fn token(&self, body: &[u8]) -> Response<Body> {
+        let params: HashMap<String, String> =
+            url::form_urlencoded::parse(body).into_owned().collect();
+        let mut state = self.state.lock().unwrap();
+
+        let granted = match params.get("grant_type").map(String::as_str) {
+            Some("authorization_code") => {
+                let pending = params.get("code").and_then(|code| state.codes.remove(code));
+
+                match (
+                    pending,
+                    params.get("redirect_uri"),
+                    params.get("code_verifier"),
+                ) {
+                    (Some(pending), Some(redirect_uri), Some(verifier)) => {
+                        pending.redirect_uri == *redirect_uri
+                            && pending.code_challenge == crypto::pkce_challenge(verifier)
+                    }
+                    _ => false,
+                }
+            }
+            Some("refresh_token") => match params.get("refresh_token") {
+                Some(refresh_token) => {
+                    let known = state.refresh_tokens.contains(refresh_token);
+                    state.refresh_tokens.retain(|token| token != refresh_token);
+                    known
+                }
+                None => false,
+            },
+            _ => false,
+        };
+
+        if !granted {
+            return status(400, json!({ "error": "invalid_grant" }));
+        }
+
+        let access_token = crypto::random_token(24);
+        let refresh_token = crypto::random_token(24);
+
+        state.access_tokens.push(access_token.clone());
+        state.refresh_tokens.push(refresh_token.clone());
+
+        status(
+            200,
+            json!({
+                "access_token": access_token,
+                "token_type": "Bearer",
+                "expires_in": FAKE_TOKEN_TTL,
+                "refresh_token": refresh_token,
+                "scope": "identify guilds guilds.members.read",
+            }),
+        )
+    }
+
+    /// Responds with `body` if the request carries an issued access token.
+    fn with_user(&self, req: &Request<Body>, body: serde_json::Value) -> Response<Body> {
+        let token = req
+            .headers()
+            .get("Authorization")
+            .and_then(|header| header.to_str().ok())
+            .and_then(|header| header.strip_prefix("Bearer "));
+
+        match token {
+            Some(token)
+                if self
+                    .state
+                    .lock()
+                    .unwrap()
+                    .access_tokens
+                    .iter()
+                    .any(|t| t == token) =>
+            {
+                status(200, body)
+            }
+            _ => status(401, json!({ "message": "401: Unauthorized", "code": 0 })),
+        }
+    }
+
+    /// Serves a fake guild, or one of its channels or roles, to the bot.
+    fn bot_guild(
+        &self,
+        req: &Request<Body>,
+        guild_id: &str,
+        resource: Option<&str>,
+    ) -> Response<Body> {
+        let is_bot = req
+            .headers()
+            .get("Authorization")
+            .and_then(|header| header.to_str().ok())
+            .map(|header| header.starts_with("Bot "))
+            .unwrap_or(false);
+
+        if !is_bot {
+            return status(401, json!({ "message": "401: Unauthorized", "code": 0 }));
+        }
+
+        let (id, name) = match FAKE_GUILDS.iter().find(|(id, _)| *id == guild_id) {
+            Some(guild) => guild,
+            None => return status(404, json!({ "message": "Unknown Guild", "code": 10004 })),
+        };
+
+        match resource {
+            None => status(
+                200,
+                json!({ "id": id, "name": name, "icon": null, "owner_id": FAKE_USER_ID }),
+            ),
+            Some("channels") => status(
+                200,
+                json!([
+                    { "id": "300000000000000001", "name": "Sector C", "type": 4, "position": 0 },
+                    { "id": "300000000000000002", "name": "general", "type": 0, "position": 1, "parent_id": "300000000000000001" },
+                    { "id": "300000000000000003", "name": "mod-log", "type": 0, "position": 2, "parent_id": "300000000000000001" },
+                    { "id": "300000000000000004", "name": "appeals", "type": 0, "position": 3, "parent_id": "300000000000000001" },
+                ]),
+            ),
+            Some("roles") => status(
+                200,
+                json!([
+                    { "id": id, "name": "@everyone", "position": 0, "color": 0, "managed": false },
+                    { "id": "400000000000000001", "name": "Scientist", "position": 2, "color": 16750848, "managed": false },
+                    { "id": "400000000000000002", "name": "Muted", "position": 1, "color": 0, "managed": false },
+                ]),
+            ),
+            Some(_) => status(404, json!({ "message": "404: Not Found", "code": 0 })),
+        }
+    }
+
+    /// Accepts messages from the bot to any channel of the fake guilds.
+    fn bot_message(&self, req: &Request<Body>, channel_id: &str) -> Response<Body> {
+        let is_bot = req
+            .headers()
+            .get("Authorization")
+            .and_then(|header| header.to_str().ok())
+            .map(|header| header.starts_with("Bot "))
+            .unwrap_or(false);
+
+        if !is_bot {
+            return status(401, json!({ "message": "401: Unauthorized", "code": 0 }));
+        }
+
+        if !channel_id.starts_with("30000000000000000") {
+            return status(404, json!({ "message": "Unknown Channel", "code": 10003 }));
+        }
+
+        status(
+            200,
+            json!({ "id": crypto::random_token(8), "channel_id": channel_id }),
+        )
+    }
+}
+
+/// The fake user is a Scientist in every fake guild.
+fn fake_member() -> serde_json::Value {
+    json!({ "roles": ["400000000000000001"], "joined_at": "2004-11-16T00:00:00+00:00" })
+}
+
+fn query(query: &str) -> HashMap<String, String> {
+    url::form_urlencoded::parse(query.as_bytes())
+        .into_owned()
+        .collect()
+}
+
+fn status(status: u16, body: serde_json::Value) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::discord::DiscordClient;
+
+    const REDIRECT_URI: &str = "http://localhost:8080/oauth/callback";
+
+    fn client(addr: SocketAddr) -> DiscordClient {
+        DiscordClient::new(&ApiConfig {
+            discord_client_id: "1".to_string(),
+            discord_client_secret: "secret".to_string(),
+            discord_bot_token: Some("fake".to_string()),
+            discord_api_url: format!("http://{}/api", addr),
+            public_url: None,
+            trusted_proxies: Vec::new(),
+            oauth_return_origins: Vec::new(),
+            staff_user_ids: Vec::new(),
+        })
+    }
+
+    /// Follows the authorize URL like a browser would, returning the code and
+    /// state the fake redirected back with.
+    async fn authorize(discord: &DiscordClient, verifier: &str) -> (String, String) {
+        let url = discord.authorize_url(REDIRECT_URI, "state", &crypto::pkce_challenge(verifier));
+
+        let resp = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .unwrap()
+            .get(url)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 302);
+
+        let location = url::Url::parse(resp.headers()["Location"].to_str().unwrap()).unwrap();
+        assert_eq!(
+            location[..url::Position::AfterPath],
+            *REDIRECT_URI,
+            "redirects back to the callback"
+        );
+
+        let params: HashMap<_, _> = location.query_pairs().into_owned().collect();
+        (params["code"].clone(), params["state"].clone())
+    }
+
+    #[tokio::test]
+    async fn login_and_list_guilds() {
+        let addr = FakeDiscord::spawn(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0));
+        let discord = client(addr);
+
+        let verifier = crypto::random_token(32);
+        let (code, state) = authorize(&discord, &verifier).await;
+        assert_eq!(state, "state");
+
+        let tokens = discord
+            .exchange_code(&code, REDIRECT_URI, &verifier)
+            .await
+            .unwrap();
+
+        // codes are single use
+        assert!(discord
+            .exchange_code(&code, REDIRECT_URI, &verifier)
+            .await
+            .is_err());
+
+        let user = discord.current_user(&tokens.access_token).await.unwrap();
+        assert_eq!(user.id, FAKE_USER_ID);
+
+        let guilds = discord.user_guilds(&tokens.access_token).await.unwrap();
+        let ids: Vec<&str> = guilds.iter().map(|guild| guild.id.as_str()).collect();
+        assert_eq!(ids, FAKE_GUILDS.map(|(id, _)| id));
+
+        let member = discord
+            .user_member(&tokens.access_token, FAKE_GUILDS[0].0)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(member.roles, ["400000000000000001"]);
+
+        assert!(discord.current_user("unknown").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn checks_pkce_and_redirect_uri() {
+        let addr = FakeDiscord::spawn(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0));
+        let discord = client(addr);
+
+        let verifier = crypto::random_token(32);
+
+        let (code, _) = authorize(&discord, &verifier).await;
+        assert!(discord
+            .exchange_code(&code, REDIRECT_URI, "wrong verifier")
+            .await
+            .is_err());
+
+        let (code, _) = authorize(&discord, &verifier).await;
+        assert!(discord
+            .exchange_code(&code, "http://evil.example/callback", &verifier)
+            .await
+            .is_err());
+
+        // the code is sent as a single form value, whatever it contains
+        let (code, _) = authorize(&discord, &verifier).await;
+        assert!(discord
+            .exchange_code(&format!("{}&scope=bot", code), REDIRECT_URI, &verifier)
+            .await
+            .is_err());
+        assert!(discord
+            .exchange_code(&code, REDIRECT_URI, &verifier)
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn rotates_refresh_tokens() {
+        let addr = FakeDiscord::spawn(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0));
+        let discord = client(addr);
+
+        let verifier = crypto::random_token(32);
+        let (code, _) = authorize(&discord, &verifier).await;
+        let tokens = discord
+            .exchange_code(&code, REDIRECT_URI, &verifier)
+            .await
+            .unwrap();
+
+        let refreshed = discord
+            .refresh_token(&tokens.refresh_token)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_ne!(refreshed.access_token, tokens.access_token);
+
+        // the old refresh token was used up by the rotation
+        assert!(discord
+            .refresh_token(&tokens.refresh_token)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn serves_guilds_to_the_bot() {
+        let addr = FakeDiscord::spawn(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0));
+        let discord = client(addr);
+
+        let channels = discord
+            .bot_channels(FAKE_GUILDS[0].0)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(channels
+            .iter()
+            .any(|channel| channel.name.as_deref() == Some("appeals")));
+
+        let roles = discord.bot_roles(FAKE_GUILDS[0].0).await.unwrap().unwrap();
+        assert!(roles.iter().any(|role| role.id == FAKE_GUILDS[0].0));
+
+        assert!(discord
+            .bot_channels("200000000000000009")
+            .await
+            .unwrap()
+            .is_none());
+    }
+}
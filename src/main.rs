@@ -10,30 +10,67 @@ use hyper::{Body, Method, Request, Response};
 
 use crate::mongo::Database;
 
+mod api_keys;
 mod auth;
+mod cache;
+mod crypto;
 mod discord;
 mod endpoints;
 mod errors;
+#[cfg(debug_assertions)]
+mod fake_discord;
 mod middleware;
 mod mongo;
+mod notifications;
 mod oauth;
+mod permissions;
+mod references;
+mod sessions;
 mod structs;
+mod users;
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
 const HOST: &str = "0.0.0.0";
-const PORT: u16 = 8080;
+const DEFAULT_PORT: u16 = 8080;
 
 pub struct Api {
     config: ApiConfig,
     db: mongo::Database,
-    client: reqwest::Client,
+    /// Shared with background tasks, such as appeal notifications.
+    discord: Arc<discord::DiscordClient>,
+    cache: cache::Cache,
     jwt: auth::Jwt,
+    /// Shared with background tasks, such as appeal notifications.
+    cipher: Arc<crypto::TokenCipher>,
+    /// Serializes Discord token refreshes per user, see
+    /// [`Api::discord_access_token`].
+    discord_refreshes: std::sync::Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>,
 }
 
 pub struct ApiConfig {
     pub discord_client_id: String,
     pub discord_client_secret: String,
+    pub discord_bot_token: Option<String>,
+    pub discord_api_url: String,
+    /// The externally visible origin of the API, e.g. `https://api.blackmesa.bot`.
+    /// When unset it is derived from the request.
+    pub public_url: Option<String>,
+    /// Peers whose `X-Forwarded-Proto` and `X-Forwarded-Host` headers are trusted.
+    pub trusted_proxies: Vec<IpAddr>,
+    /// Origins the OAuth callback may redirect back to through `return_to`.
+    pub oauth_return_origins: Vec<String>,
+    /// Users with staff access on top of those flagged in the database.
+    pub staff_user_ids: Vec<String>,
+}
+
+fn env_list(name: &str) -> Vec<String> {
+    std::env::var(name)
+        .unwrap_or_default()
+        .split(',')
+        .map(|item| item.trim().to_string())
+        .filter(|item| !item.is_empty())
+        .collect()
 }
 
 impl Default for ApiConfig {
@@ -43,6 +80,24 @@ impl Default for ApiConfig {
                 .expect("DISCORD_CLIENT_ID must be set"),
             discord_client_secret: std::env::var("DISCORD_CLIENT_SECRET")
                 .expect("DISCORD_CLIENT_SECRET must be set"),
+            discord_bot_token: std::env::var("DISCORD_BOT_TOKEN").ok(),
+            discord_api_url: std::env::var("DISCORD_API_URL")
+                .unwrap_or_else(|_| discord::DISCORD_API_URL.to_string()),
+            public_url: std::env::var("PUBLIC_URL")
+                .ok()
+                .map(|url| url.trim_end_matches('/').to_string()),
+            trusted_proxies: env_list("TRUSTED_PROXIES")
+                .iter()
+                .map(|ip| {
+                    ip.parse()
+                        .expect("TRUSTED_PROXIES must be a list of IP addresses")
+                })
+                .collect(),
+            oauth_return_origins: env_list("OAUTH_RETURN_ORIGINS")
+                .iter()
+                .map(|origin| origin.trim_end_matches('/').to_string())
+                .collect(),
+            staff_user_ids: env_list("STAFF_USER_IDS"),
         }
     }
 }
@@ -51,34 +106,84 @@ impl Default for ApiConfig {
 async fn main() -> Result<(), Error> {
     tracing_subscriber::fmt::init();
 
-    let secret = std::env::var("JWT_SECRET")
-        .expect("JWT_SECRET must be set")
-        .bytes()
-        .collect::<Vec<u8>>();
+    let jwt_keys = auth::JwtKeys::from_env().expect("Failed to load JWT keys");
+
+    let config = ApiConfig::default();
+
+    // the fake approves every login, so it is never compiled into release builds
+    #[cfg(debug_assertions)]
+    let config = fake_discord::from_env(config);
+
+    #[cfg(not(debug_assertions))]
+    if std::env::var("FAKE_DISCORD_PORT").is_ok() {
+        tracing::warn!(
+            "FAKE_DISCORD_PORT is ignored, the fake Discord API is only in debug builds"
+        );
+    }
+
+    let port = match std::env::var("PORT") {
+        Ok(port) => port.parse().expect("PORT must be a port"),
+        Err(_) => DEFAULT_PORT,
+    };
 
     let api = Api {
-        config: ApiConfig::default(),
+        discord: Arc::new(discord::DiscordClient::new(&config)),
+        cache: cache::Cache::new(std::env::var("REDIS_URI").ok().as_deref()).await,
+        config,
         db: Database::new(&std::env::var("MONGO_URI").expect("MONGO_URI must be set")).await,
-        client: reqwest::Client::new(),
-        jwt: auth::Jwt::new(&secret),
+        jwt: auth::Jwt::new(
+            jwt_keys,
+            std::env::var("JWT_ISSUER").unwrap_or_else(|_| "black-mesa-api".to_string()),
+            std::env::var("JWT_AUDIENCE").unwrap_or_else(|_| "black-mesa".to_string()),
+        )
+        .expect("Invalid JWT key set"),
+        cipher: Arc::new(
+            crypto::TokenCipher::new(
+                &std::env::var("TOKEN_ENCRYPTION_KEY").expect("TOKEN_ENCRYPTION_KEY must be set"),
+            )
+            .expect("TOKEN_ENCRYPTION_KEY must be a base64 encoded 32 byte key"),
+        ),
+        discord_refreshes: Default::default(),
     };
 
+    api.db
+        .create_indexes()
+        .await
+        .expect("Failed to create MongoDB indexes");
+
     tracing::info!("Starting Black Mesa Public REST API v{}", VERSION);
 
     let api_ref = Arc::new(api);
-    let make_svc = hyper::service::make_service_fn(move |_| {
-        let api_ref = api_ref.clone();
-        async move {
-            Ok::<_, hyper::Error>(hyper::service::service_fn(move |req| {
-                let api_ref = api_ref.clone();
-                async move { api_ref.handle_request(req).await }
-            }))
+
+    let reload_ref = api_ref.clone();
+    tokio::spawn(async move {
+        let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+            .expect("Failed to listen for SIGHUP");
+
+        while hangup.recv().await.is_some() {
+            if let Err(err) = auth::JwtKeys::from_env().and_then(|keys| reload_ref.jwt.reload(keys))
+            {
+                tracing::error!("Error reloading JWT keys: {}", err);
+            }
         }
     });
 
+    let make_svc =
+        hyper::service::make_service_fn(move |conn: &hyper::server::conn::AddrStream| {
+            let api_ref = api_ref.clone();
+            let remote_addr = conn.remote_addr();
+            async move {
+                Ok::<_, hyper::Error>(hyper::service::service_fn(move |mut req: Request<Body>| {
+                    let api_ref = api_ref.clone();
+                    req.extensions_mut().insert(remote_addr);
+                    async move { api_ref.handle_request(req).await }
+                }))
+            }
+        });
+
     let addr = SocketAddr::new(
         IpAddr::V4(Ipv4Addr::from_str(HOST).expect("Invalid host specified")),
-        PORT,
+        port,
     );
 
     let server = hyper::Server::bind(&addr).serve(make_svc);
@@ -86,7 +191,7 @@ async fn main() -> Result<(), Error> {
     tokio::select!(
         _ = server => {},
         _ = async move {
-            tracing::info!("Black Mesa Public REST API running on http://{}:{}", HOST, PORT);
+            tracing::info!("Black Mesa Public REST API running on http://{}:{}", HOST, port);
             tokio::signal::ctrl_c().await.unwrap();
         } => {
 
@@ -109,9 +214,8 @@ impl Api {
             match *section {
                 "api" => {
                     if let Some(api_version) = path_parts.get(2) {
-                        match *api_version {
-                            "v1" => return self.handle_v1(req, path_parts).await,
-                            _ => {}
+                        if *api_version == "v1" {
+                            return self.handle_v1(req, path_parts).await;
                         }
                     }
                 }
@@ -120,10 +224,33 @@ impl Api {
                         match *operation {
                             "callback" => return self.discord_oauth(req).await,
                             "login" => return self.discord_login(req).await,
+                            "refresh" => {
+                                return match *req.method() {
+                                    Method::POST => self.oauth_refresh(req).await,
+                                    _ => self.method_not_allowed().await,
+                                }
+                            }
+                            "logout" => {
+                                let all = match path_parts.get(3) {
+                                    None => false,
+                                    Some(&"all") => true,
+                                    Some(_) => return self.not_found().await,
+                                };
+                                return match *req.method() {
+                                    Method::POST => self.oauth_logout(req, all).await,
+                                    _ => self.method_not_allowed().await,
+                                };
+                            }
                             _ => {}
                         }
                     }
                 }
+                ".well-known" if path_parts.get(2) == Some(&"jwks.json") => {
+                    return match *req.method() {
+                        Method::GET => self.jwks(req).await,
+                        _ => self.method_not_allowed().await,
+                    };
+                }
                 _ => {}
             }
         }
@@ -143,44 +270,124 @@ impl Api {
                 }
                 match auth {
                     Some(auth) => auth,
-                    None => return Ok(self.not_authorized("Unauthorized").await?),
+                    None => return self.not_authorized("Unauthorized").await,
                 }
             }
             Err(err) => {
                 tracing::error!("Error authenticating request: {}", err);
-                return Ok(self.internal_server_error().await?);
+                return self.internal_server_error().await;
             }
         };
 
         if let Some(endpoint) = path_parts.get(3) {
             return match (*endpoint, req.method()) {
-                ("guilds", &Method::GET) => self.get_guild_list(req).await,
-
-                ("guild", _) => match path_parts.get(4) {
-                    Some(guild_id) => match req.method() {
-                        &Method::GET => self.get_guild(req, *guild_id).await,
-                        &Method::POST => self.post_guild(req, *guild_id).await,
-                        &Method::PATCH => self.update_guild(req, *guild_id).await,
-                        &Method::DELETE => self.delete_guild(req, *guild_id).await,
+                ("guilds", &Method::GET) => match auth.user_id() {
+                    Some(user_id) => self.get_guild_list(req, user_id).await,
+                    None => self.user_required().await,
+                },
+
+                ("guild", _) => match (path_parts.get(4), path_parts.get(5)) {
+                    (Some(guild_id), None) => match *req.method() {
+                        Method::GET => self.get_guild(req, guild_id, &auth).await,
+                        Method::POST => self.post_guild(req, guild_id, &auth).await,
+                        Method::PATCH => self.update_guild(req, guild_id, &auth).await,
+                        Method::DELETE => self.delete_guild(req, guild_id, &auth).await,
                         _ => self.method_not_allowed().await,
                     },
-                    None => match req.method() {
-                        &Method::GET => self.get_guild_list(req).await,
+                    (Some(guild_id), Some(&"channels")) if path_parts.len() == 6 => {
+                        match *req.method() {
+                            Method::GET => self.get_guild_channels(req, guild_id, &auth).await,
+                            _ => self.method_not_allowed().await,
+                        }
+                    }
+                    (Some(guild_id), Some(&"roles")) if path_parts.len() == 6 => {
+                        match *req.method() {
+                            Method::GET => self.get_guild_roles(req, guild_id, &auth).await,
+                            _ => self.method_not_allowed().await,
+                        }
+                    }
+                    (Some(guild_id), Some(&"references")) if path_parts.len() == 6 => {
+                        match *req.method() {
+                            Method::GET => self.get_guild_references(req, guild_id, &auth).await,
+                            _ => self.method_not_allowed().await,
+                        }
+                    }
+                    (Some(guild_id), Some(&"appeals"))
+                        if path_parts.get(6) == Some(&"webhook") && path_parts.len() == 7 =>
+                    {
+                        match *req.method() {
+                            Method::GET => self.get_appeal_webhook(req, guild_id, &auth).await,
+                            Method::PUT => self.put_appeal_webhook(req, guild_id, &auth).await,
+                            Method::DELETE => {
+                                self.delete_appeal_webhook(req, guild_id, &auth).await
+                            }
+                            _ => self.method_not_allowed().await,
+                        }
+                    }
+                    (Some(guild_id), Some(&"permissions")) => match path_parts.get(6) {
+                        Some(target_id) => match *req.method() {
+                            Method::GET => {
+                                self.get_guild_permissions(req, guild_id, target_id, &auth)
+                                    .await
+                            }
+                            _ => self.method_not_allowed().await,
+                        },
+                        None => self.bad_request("a user id must be specified").await,
+                    },
+                    (Some(_), Some(_)) => self.not_found().await,
+                    (None, _) => match (req.method(), auth.user_id()) {
+                        (&Method::GET, Some(user_id)) => self.get_guild_list(req, user_id).await,
+                        (&Method::GET, None) => self.user_required().await,
 
                         _ => self.method_not_allowed().await,
                     },
                 },
                 ("appeals", _) => match path_parts.get(4) {
-                    Some(id) => match req.method() {
-                        &Method::GET => self.get_appeals(req, *id).await,
-                        &Method::POST => self.create_appeal(req, *id, &auth.sub).await,
-                        &Method::PATCH => self.update_appeal(req, *id, &auth.sub).await,
-                        &Method::DELETE => self.delete_appeal(req, *id, &auth.sub).await,
-                        &Method::OPTIONS => self.options_appeal(req).await,
+                    Some(id) => match (req.method().clone(), auth.user_id()) {
+                        (Method::GET, _) => self.get_appeals(req, id, &auth).await,
+                        (Method::PATCH, _) => self.update_appeal(req, id, &auth).await,
+                        (Method::OPTIONS, _) => self.options_appeal(req).await,
+                        (Method::POST, Some(user_id)) => self.create_appeal(req, id, user_id).await,
+                        (Method::DELETE, Some(user_id)) => {
+                            self.delete_appeal(req, id, user_id).await
+                        }
+                        (Method::POST | Method::DELETE, None) => self.user_required().await,
                         _ => self.method_not_allowed().await,
                     },
                     None => self.bad_request("an id must be specified").await,
                 },
+                ("users", _) => {
+                    let claims = match &auth {
+                        auth::Principal::User(claims) => claims,
+                        auth::Principal::ApiKey(_) => return self.user_required().await,
+                    };
+
+                    match (path_parts.get(4), path_parts.get(5), req.method()) {
+                        (Some(&"@me"), None, &Method::GET) => {
+                            self.get_current_user(req, claims).await
+                        }
+                        (Some(&"@me"), Some(&"sessions"), &Method::DELETE) => {
+                            self.delete_current_sessions(req, claims).await
+                        }
+                        (Some(&"@me"), None | Some(&"sessions"), _) => {
+                            self.method_not_allowed().await
+                        }
+                        _ => self.not_found().await,
+                    }
+                }
+                ("keys", _) => {
+                    let user_id = match auth.user_id() {
+                        Some(user_id) => user_id,
+                        None => return self.user_required().await,
+                    };
+
+                    match (path_parts.get(4), req.method()) {
+                        (None, &Method::GET) => self.get_api_keys(req, user_id).await,
+                        (None, &Method::POST) => self.create_api_key(req, user_id).await,
+                        (Some(id), &Method::DELETE) => self.delete_api_key(req, id, user_id).await,
+                        _ => self.method_not_allowed().await,
+                    }
+                }
 
                 _ => self.not_found().await,
             };
@@ -204,9 +411,9 @@ impl Api {
                             Some(self.bad_request("invalid query parameters").await?),
                         ));
                     }
-                    params.insert(parts[0].to_string(), parts[1].to_string());
+                    params.insert(decode_query_part(parts[0]), decode_query_part(parts[1]));
                 }
-                return Ok((Some(params), None));
+                Ok((Some(params), None))
             }
             None => {
                 return Ok((
@@ -231,6 +438,18 @@ impl Api {
             .unwrap())
     }
 
+    async fn forbidden(&self, msg: &str) -> Result<Response<Body>, hyper::Error> {
+        Ok(Response::builder()
+            .status(403)
+            .body(Body::from(format!("Forbidden: {}", msg)))
+            .unwrap())
+    }
+
+    async fn user_required(&self) -> Result<Response<Body>, hyper::Error> {
+        self.forbidden("this endpoint requires a user session")
+            .await
+    }
+
     async fn method_not_allowed(&self) -> Result<Response<Body>, hyper::Error> {
         Ok(Response::builder()
             .status(405)
@@ -245,6 +464,21 @@ impl Api {
             .unwrap())
     }
 
+    /// Responds to an error from talking to Discord, which is a 503 when the
+    /// request could not be made within the rate limit budget.
+    async fn discord_error(&self, err: &Error) -> Result<Response<Body>, hyper::Error> {
+        match err.retry_after() {
+            Some(retry_after) => Ok(Response::builder()
+                .status(503)
+                .header("Retry-After", retry_after.as_secs_f64().ceil() as u64)
+                .body(Body::from(
+                    "Service unavailable: discord rate limit reached",
+                ))
+                .unwrap()),
+            None => self.internal_server_error().await,
+        }
+    }
+
     async fn internal_server_error(&self) -> Result<Response<Body>, hyper::Error> {
         Ok(Response::builder()
             .status(500)
@@ -262,7 +496,7 @@ impl Api {
     {
         let json = match serde_json::to_string(&json) {
             Ok(json) => json,
-            Err(_) => return Ok(self.internal_server_error().await?),
+            Err(_) => return self.internal_server_error().await,
         };
 
         Ok(Response::builder()
@@ -272,3 +506,10 @@ impl Api {
             .unwrap())
     }
 }
+
+fn decode_query_part(part: &str) -> String {
+    url::form_urlencoded::parse(part.as_bytes())
+        .next()
+        .map(|(decoded, _)| decoded.into_owned())
+        .unwrap_or_default()
+}
@@ -1,21 +1,79 @@
-use hyper::{Body, Request, Response};
+use std::net::{IpAddr, SocketAddr};
 
-use crate::{auth::Claims, Api};
+use hyper::{Body, Method, Request, Response};
+
+use crate::{
+    api_keys::API_KEY_PREFIX,
+    auth::Principal,
+    permissions,
+    sessions::{CSRF_COOKIE, CSRF_HEADER, SESSION_COOKIE},
+    Api,
+};
 
 impl Api {
+    /// Returns the origin clients reach the API at, preferring the configured
+    /// `PUBLIC_URL`. Otherwise the `Host` header is used, and forwarded
+    /// headers are only honored when the peer is a trusted proxy.
+    pub fn public_base_url(&self, req: &Request<Body>) -> String {
+        request_base_url(
+            self.config.public_url.as_deref(),
+            &self.config.trusted_proxies,
+            req,
+        )
+    }
+
+    /// Authenticates a request from its `Authorization: Bearer` header, which
+    /// holds either an API JWT or an API key, or from a session cookie.
+    ///
+    /// Browsers attach cookies to cross-site requests on their own, so cookie
+    /// authenticated requests that change state also have to pass the
+    /// double-submit CSRF check.
     pub async fn authenticate(
         &self,
         req: &Request<Body>,
-    ) -> Result<(Option<Response<Body>>, Option<Claims>), hyper::Error> {
-        let token = req
+    ) -> Result<(Option<Response<Body>>, Option<Principal>), hyper::Error> {
+        let bearer = req
             .headers()
             .get("Authorization")
             .and_then(|token| token.to_str().ok())
             .and_then(|header| header.strip_prefix("Bearer ").map(|t| t.to_string()));
 
-        let token = match token {
+        let token = match bearer {
             Some(token) => token,
-            None => {
+            None => match get_cookie(req, SESSION_COOKIE) {
+                Some(token) => {
+                    if !csrf_valid(req) {
+                        return Ok((
+                            Some(self.forbidden("missing or invalid csrf token").await?),
+                            None,
+                        ));
+                    }
+
+                    token
+                }
+                None => {
+                    return Ok((
+                        Some(self.not_authorized("invalid authorization header").await?),
+                        None,
+                    ));
+                }
+            },
+        };
+
+        if token.starts_with(API_KEY_PREFIX) {
+            return match self.verify_api_key(&token).await {
+                Ok(Some(key)) => Ok((None, Some(Principal::ApiKey(key)))),
+                Ok(None) => Ok((Some(self.not_authorized("invalid api key").await?), None)),
+                Err(err) => {
+                    tracing::error!("Error verifying api key: {}", err);
+                    Ok((Some(self.internal_server_error().await?), None))
+                }
+            };
+        }
+
+        let claims = match self.jwt.verify_jwt(&token) {
+            Ok(claims) => claims,
+            Err(_) => {
                 return Ok((
                     Some(self.not_authorized("invalid authorization header").await?),
                     None,
@@ -23,16 +81,296 @@ impl Api {
             }
         };
 
-        let claims = match self.jwt.verify_jwt(&token) {
-            Ok(claims) => claims,
-            Err(_) => {
+        match self.is_token_revoked(&claims).await {
+            Ok(false) => {}
+            Ok(true) => {
                 return Ok((
-                    Some(self.not_authorized("invalid authorization header").await?),
+                    Some(self.not_authorized("token has been revoked").await?),
                     None,
                 ));
             }
+            Err(err) => {
+                tracing::error!("Error checking token revocation: {}", err);
+                return Ok((Some(self.internal_server_error().await?), None));
+            }
+        }
+
+        Ok((None, Some(Principal::User(claims))))
+    }
+
+    /// Checks that `principal` holds `permission` in `guild_id`, returning the
+    /// response to send back when it does not.
+    ///
+    /// API keys need the permission in their scope, and their owner needs to
+    /// still hold it in the guild, so a key never outlives its owner's access.
+    ///
+    /// Staff sessions are let through on every guild, and each such access is
+    /// audited. API keys never carry staff access.
+    pub async fn authorize_guild(
+        &self,
+        principal: &Principal,
+        guild_id: &str,
+        permission: &str,
+    ) -> Result<Option<Response<Body>>, hyper::Error> {
+        let allowed = match principal {
+            Principal::User(claims) if claims.staff => self
+                .audit_staff_access(&claims.sub, guild_id, permission)
+                .await
+                .map(|_| true),
+            Principal::User(claims) => {
+                self.check_permission(guild_id, &claims.sub, permission)
+                    .await
+            }
+            Principal::ApiKey(key) => {
+                if permissions::resolve_scope(key, guild_id).has(permission) {
+                    self.check_permission(guild_id, &key.owner_id, permission)
+                        .await
+                } else {
+                    Ok(false)
+                }
+            }
         };
 
-        Ok((None, Some(claims)))
+        match allowed {
+            Ok(true) => Ok(None),
+            Ok(false) => Ok(Some(
+                self.forbidden(&format!("missing permission {}", permission))
+                    .await?,
+            )),
+            Err(err) => {
+                tracing::error!("Error checking permission: {}", err);
+                Ok(Some(self.discord_error(&err).await?))
+            }
+        }
+    }
+
+    /// Guilds without a stored config have nobody to grant permissions, so the
+    /// first config may only be created by the Discord owner of the guild.
+    pub async fn authorize_bootstrap(
+        &self,
+        guild_id: &str,
+        user_id: &str,
+    ) -> Result<Option<Response<Body>>, hyper::Error> {
+        if !self.discord.has_bot_token() {
+            return Ok(Some(
+                self.forbidden("guild has no config and ownership cannot be verified")
+                    .await?,
+            ));
+        }
+
+        match self.discord.bot_guild(guild_id).await {
+            Ok(Some(guild)) if guild.owner_id == user_id => Ok(None),
+            Ok(_) => Ok(Some(
+                self.forbidden("only the guild owner can create the initial config")
+                    .await?,
+            )),
+            Err(err) => {
+                tracing::error!("Error fetching guild from discord: {}", err);
+                Ok(Some(self.discord_error(&err).await?))
+            }
+        }
+    }
+}
+
+/// See [`Api::public_base_url`].
+fn request_base_url(
+    public_url: Option<&str>,
+    trusted_proxies: &[IpAddr],
+    req: &Request<Body>,
+) -> String {
+    if let Some(public_url) = public_url {
+        return public_url.to_string();
+    }
+
+    let trusted = req
+        .extensions()
+        .get::<SocketAddr>()
+        .map(|addr| trusted_proxies.contains(&addr.ip()))
+        .unwrap_or(false);
+
+    let header = |name: &str| {
+        req.headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            // proxies append to the list, the first entry is the client facing one
+            .and_then(|value| value.split(',').next())
+            .map(|value| value.trim().to_string())
+            .filter(|value| !value.is_empty())
+    };
+
+    let proto = trusted
+        .then(|| header("X-Forwarded-Proto"))
+        .flatten()
+        .filter(|proto| proto == "http" || proto == "https")
+        .unwrap_or_else(|| "http".to_string());
+
+    let host = trusted
+        .then(|| header("X-Forwarded-Host"))
+        .flatten()
+        .or_else(|| header("Host"))
+        .unwrap_or_else(|| "localhost".to_string());
+
+    format!("{}://{}", proto, host)
+}
+
+/// Checks the double-submit CSRF token of a cookie authenticated request.
+/// Safe methods do not need one.
+pub fn csrf_valid(req: &Request<Body>) -> bool {
+    if matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS) {
+        return true;
+    }
+
+    let header = req
+        .headers()
+        .get(CSRF_HEADER)
+        .and_then(|header| header.to_str().ok());
+
+    match (get_cookie(req, CSRF_COOKIE), header) {
+        (Some(cookie), Some(header)) => !cookie.is_empty() && cookie == header,
+        _ => false,
+    }
+}
+
+/// Reads a cookie from the `Cookie` headers of a request.
+pub fn get_cookie(req: &Request<Body>, name: &str) -> Option<String> {
+    req.headers()
+        .get_all("Cookie")
+        .iter()
+        .filter_map(|header| header.to_str().ok())
+        .flat_map(|header| header.split(';'))
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(method: Method, cookies: &[&str], csrf_header: Option<&str>) -> Request<Body> {
+        let mut req = Request::builder().method(method).uri("/api/v1/guild/1");
+
+        for cookie in cookies {
+            req = req.header("Cookie", *cookie);
+        }
+        if let Some(header) = csrf_header {
+            req = req.header(CSRF_HEADER, header);
+        }
+
+        req.body(Body::empty()).unwrap()
+    }
+
+    fn forwarded(peer: &str) -> Request<Body> {
+        let mut req = Request::builder()
+            .uri("/oauth/login")
+            .header("Host", "10.0.0.5:8080")
+            .header("X-Forwarded-Proto", "https")
+            .header("X-Forwarded-Host", "api.example.com, 10.0.0.5")
+            .body(Body::empty())
+            .unwrap();
+        req.extensions_mut()
+            .insert(SocketAddr::new(peer.parse().unwrap(), 40000));
+        req
+    }
+
+    #[test]
+    fn forwarded_headers_need_a_trusted_peer() {
+        let proxies = ["10.0.0.1".parse().unwrap()];
+
+        assert_eq!(
+            request_base_url(None, &proxies, &forwarded("10.0.0.1")),
+            "https://api.example.com"
+        );
+        assert_eq!(
+            request_base_url(None, &proxies, &forwarded("203.0.113.7")),
+            "http://10.0.0.5:8080"
+        );
+        assert_eq!(
+            request_base_url(None, &[], &forwarded("10.0.0.1")),
+            "http://10.0.0.5:8080"
+        );
+
+        // no peer address at all is not trusted either
+        let mut req = forwarded("10.0.0.1");
+        req.extensions_mut().clear();
+        assert_eq!(
+            request_base_url(None, &proxies, &req),
+            "http://10.0.0.5:8080"
+        );
+    }
+
+    #[test]
+    fn public_url_takes_precedence() {
+        let proxies = ["10.0.0.1".parse().unwrap()];
+
+        assert_eq!(
+            request_base_url(
+                Some("https://public.example.com"),
+                &proxies,
+                &forwarded("10.0.0.1")
+            ),
+            "https://public.example.com"
+        );
+    }
+
+    #[test]
+    fn safe_methods_need_no_csrf_token() {
+        for method in [Method::GET, Method::HEAD, Method::OPTIONS] {
+            assert!(csrf_valid(&request(method, &[], None)));
+        }
+    }
+
+    #[test]
+    fn csrf_token_has_to_match_the_cookie() {
+        let cookie = "bm_session=jwt; bm_csrf=token";
+
+        assert!(csrf_valid(&request(Method::POST, &[cookie], Some("token"))));
+
+        assert!(!csrf_valid(&request(Method::POST, &[cookie], None)));
+        assert!(!csrf_valid(&request(
+            Method::POST,
+            &["bm_session=jwt"],
+            Some("token")
+        )));
+        assert!(!csrf_valid(&request(
+            Method::PATCH,
+            &[cookie],
+            Some("other")
+        )));
+        assert!(!csrf_valid(&request(
+            Method::DELETE,
+            &["bm_csrf="],
+            Some("")
+        )));
+    }
+
+    #[test]
+    fn reads_cookies_across_headers() {
+        let req = request(
+            Method::POST,
+            &["bm_session=jwt", "theme=dark; bm_csrf=token"],
+            Some("token"),
+        );
+
+        assert_eq!(get_cookie(&req, "bm_session").as_deref(), Some("jwt"));
+        assert_eq!(get_cookie(&req, "theme").as_deref(), Some("dark"));
+        assert!(csrf_valid(&req));
+    }
+
+    #[test]
+    fn cookie_names_match_exactly() {
+        let req = request(
+            Method::POST,
+            &["bm_csrf_old=stale; xbm_csrf=other"],
+            Some("stale"),
+        );
+
+        assert_eq!(get_cookie(&req, CSRF_COOKIE), None);
+        assert!(!csrf_valid(&req));
+
+        let req = request(Method::GET, &["bm_csrf=token; bm_csrf_old=stale"], None);
+        assert_eq!(get_cookie(&req, CSRF_COOKIE).as_deref(), Some("token"));
+        assert_eq!(get_cookie(&req, "bm_csrf_old").as_deref(), Some("stale"));
+        assert_eq!(get_cookie(&req, "bm"), None);
     }
 }
@@ -1,13 +1,18 @@
 use mongodb::{
     bson::doc,
-    options::FindOneAndUpdateOptions,
+    options::{FindOneAndUpdateOptions, IndexOptions},
     results::{DeleteResult, InsertOneResult},
-    Client, Collection,
+    Client, Collection, IndexModel,
 };
 
 use futures_util::StreamExt;
 
-use crate::structs::{Appeal, Config, Guild};
+use crate::discord::DiscordUser;
+use crate::sessions::PREVIOUS_REFRESH_HASHES;
+use crate::structs::{
+    ApiKey, Appeal, AppealContent, AppealStatus, AppealWebhook, Config, DiscordToken, Guild,
+    OauthState, RevokedToken, Session, StaffAccess, UserFlags,
+};
 
 #[derive(Clone, Debug)]
 pub struct Database {
@@ -23,6 +28,59 @@ impl Database {
         Self { client }
     }
 
+    /// Creates the indexes the API relies on. Creating an index that already
+    /// exists is a no-op, so this is safe to run on every start.
+    pub async fn create_indexes(&self) -> Result<(), mongodb::error::Error> {
+        let db = self.client.database("black-mesa");
+
+        let ttl = IndexOptions::builder()
+            .expire_after(std::time::Duration::ZERO)
+            .build();
+
+        db.collection::<RevokedToken>("revoked_tokens")
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! { "expires_at": 1 })
+                    .options(ttl.clone())
+                    .build(),
+                None,
+            )
+            .await?;
+
+        db.collection::<RevokedToken>("revoked_tokens")
+            .create_index(IndexModel::builder().keys(doc! { "jti": 1 }).build(), None)
+            .await?;
+
+        db.collection::<Session>("sessions")
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! { "expires_at": 1 })
+                    .options(ttl.clone())
+                    .build(),
+                None,
+            )
+            .await?;
+
+        db.collection::<OauthState>("oauth_states")
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! { "expires_at": 1 })
+                    .options(ttl)
+                    .build(),
+                None,
+            )
+            .await?;
+
+        db.collection::<Session>("sessions")
+            .create_index(
+                IndexModel::builder().keys(doc! { "user_id": 1 }).build(),
+                None,
+            )
+            .await?;
+
+        Ok(())
+    }
+
     #[tracing::instrument(skip(self))]
     pub async fn get_guild(&self, guild_id: &str) -> Result<Option<Config>, mongodb::error::Error> {
         let guilds: Collection<Guild> = self.client.database("black-mesa").collection("guilds");
@@ -35,6 +93,27 @@ impl Database {
         }
     }
 
+    /// Returns the stored guilds out of `guild_ids`.
+    #[tracing::instrument(skip(self))]
+    pub async fn get_guilds(
+        &self,
+        guild_ids: &[String],
+    ) -> Result<Vec<Guild>, mongodb::error::Error> {
+        let guilds: Collection<Guild> = self.client.database("black-mesa").collection("guilds");
+
+        let mut res = guilds
+            .find(doc! { "guild_id": { "$in": guild_ids } }, None)
+            .await?;
+
+        let mut guilds = Vec::new();
+
+        while let Some(result) = res.next().await {
+            guilds.push(result?);
+        }
+
+        Ok(guilds)
+    }
+
     #[tracing::instrument(skip(self))]
     pub async fn guild_exists(&self, guild_id: &str) -> Result<bool, mongodb::error::Error> {
         let guilds: Collection<Guild> = self.client.database("black-mesa").collection("guilds");
@@ -154,27 +233,417 @@ impl Database {
         Ok(res)
     }
 
-    pub async fn update_appeal(
+    pub async fn get_appeal(&self, uuid: &str) -> Result<Option<Appeal>, mongodb::error::Error> {
+        let appeals: Collection<Appeal> = self.client.database("black-mesa").collection("appeals");
+
+        let res = appeals.find_one(doc! { "id": uuid }, None).await?;
+
+        Ok(res)
+    }
+
+    /// Replaces the content of an appeal, as long as it belongs to `user_id`
+    /// and has not been decided yet.
+    pub async fn update_appeal_content(
         &self,
         uuid: &str,
         user_id: &str,
-        appeal: Appeal,
+        content: Vec<AppealContent>,
+    ) -> Result<Option<Appeal>, mongodb::error::Error> {
+        let appeals: Collection<Appeal> = self.client.database("black-mesa").collection("appeals");
+
+        let options = FindOneAndUpdateOptions::builder()
+            .return_document(mongodb::options::ReturnDocument::After)
+            .build();
+
+        let res = appeals
+            .find_one_and_update(
+                doc! { "id": uuid, "user_id": user_id, "status": AppealStatus::Pending.to_string() },
+                doc! { "$set" : { "content": bson::to_bson(&content)? } },
+                options,
+            )
+            .await?;
+
+        Ok(res)
+    }
+
+    pub async fn update_appeal_status(
+        &self,
+        uuid: &str,
+        status: AppealStatus,
+        status_reason: Option<String>,
     ) -> Result<Option<Appeal>, mongodb::error::Error> {
         let appeals: Collection<Appeal> = self.client.database("black-mesa").collection("appeals");
 
         let options = FindOneAndUpdateOptions::builder()
-            .upsert(true)
             .return_document(mongodb::options::ReturnDocument::After)
             .build();
 
         let res = appeals
             .find_one_and_update(
-                doc! { "id": uuid, "user_id": user_id },
-                doc! { "$set" : appeal },
+                doc! { "id": uuid },
+                doc! { "$set" : { "status": status.to_string(), "status_reason": status_reason } },
+                options,
+            )
+            .await?;
+
+        Ok(res)
+    }
+
+    pub async fn get_appeal_webhook(
+        &self,
+        guild_id: &str,
+    ) -> Result<Option<AppealWebhook>, mongodb::error::Error> {
+        let webhooks: Collection<AppealWebhook> = self
+            .client
+            .database("black-mesa")
+            .collection("appeal_webhooks");
+
+        let res = webhooks
+            .find_one(doc! { "guild_id": guild_id }, None)
+            .await?;
+
+        Ok(res)
+    }
+
+    pub async fn set_appeal_webhook(
+        &self,
+        webhook: AppealWebhook,
+    ) -> Result<(), mongodb::error::Error> {
+        let webhooks: Collection<AppealWebhook> = self
+            .client
+            .database("black-mesa")
+            .collection("appeal_webhooks");
+
+        let options = mongodb::options::ReplaceOptions::builder()
+            .upsert(true)
+            .build();
+
+        webhooks
+            .replace_one(doc! { "guild_id": &webhook.guild_id }, &webhook, options)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn delete_appeal_webhook(&self, guild_id: &str) -> Result<(), mongodb::error::Error> {
+        let webhooks: Collection<AppealWebhook> = self
+            .client
+            .database("black-mesa")
+            .collection("appeal_webhooks");
+
+        webhooks
+            .delete_one(doc! { "guild_id": guild_id }, None)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn get_discord_token(
+        &self,
+        user_id: &str,
+    ) -> Result<Option<DiscordToken>, mongodb::error::Error> {
+        let tokens: Collection<DiscordToken> = self
+            .client
+            .database("black-mesa")
+            .collection("discord_tokens");
+
+        let res = tokens.find_one(doc! { "user_id": user_id }, None).await?;
+
+        Ok(res)
+    }
+
+    pub async fn set_discord_token(
+        &self,
+        token: DiscordToken,
+    ) -> Result<(), mongodb::error::Error> {
+        let tokens: Collection<DiscordToken> = self
+            .client
+            .database("black-mesa")
+            .collection("discord_tokens");
+
+        let options = mongodb::options::ReplaceOptions::builder()
+            .upsert(true)
+            .build();
+
+        tokens
+            .replace_one(doc! { "user_id": &token.user_id }, &token, options)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Caches the Discord profile of a user as of their last login.
+    pub async fn set_discord_user(&self, user: &DiscordUser) -> Result<(), mongodb::error::Error> {
+        let users: Collection<DiscordUser> = self
+            .client
+            .database("black-mesa")
+            .collection("discord_users");
+
+        let options = mongodb::options::ReplaceOptions::builder()
+            .upsert(true)
+            .build();
+
+        users
+            .replace_one(doc! { "id": &user.id }, user, options)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn get_discord_user(
+        &self,
+        user_id: &str,
+    ) -> Result<Option<DiscordUser>, mongodb::error::Error> {
+        let users: Collection<DiscordUser> = self
+            .client
+            .database("black-mesa")
+            .collection("discord_users");
+
+        let res = users.find_one(doc! { "id": user_id }, None).await?;
+
+        Ok(res)
+    }
+
+    /// Deletes the Discord token of `user_id`, unless it was replaced since
+    /// `refresh_token` was read.
+    pub async fn delete_discord_token(
+        &self,
+        user_id: &str,
+        refresh_token: &str,
+    ) -> Result<(), mongodb::error::Error> {
+        let tokens: Collection<DiscordToken> = self
+            .client
+            .database("black-mesa")
+            .collection("discord_tokens");
+
+        tokens
+            .delete_one(
+                doc! { "user_id": user_id, "refresh_token": refresh_token },
+                None,
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn create_session(&self, session: Session) -> Result<(), mongodb::error::Error> {
+        let sessions: Collection<Session> =
+            self.client.database("black-mesa").collection("sessions");
+
+        sessions.insert_one(session, None).await?;
+
+        Ok(())
+    }
+
+    pub async fn get_session(&self, id: &str) -> Result<Option<Session>, mongodb::error::Error> {
+        let sessions: Collection<Session> =
+            self.client.database("black-mesa").collection("sessions");
+
+        let res = sessions.find_one(doc! { "id": id }, None).await?;
+
+        Ok(res)
+    }
+
+    /// Swaps the refresh token and access token of a session. Only succeeds
+    /// while `old_hash` is still the current refresh token, so a refresh token
+    /// can never be redeemed twice.
+    pub async fn rotate_session(
+        &self,
+        id: &str,
+        old_hash: &str,
+        new_hash: &str,
+        access_jti: &str,
+        expires_at: bson::DateTime,
+    ) -> Result<Option<Session>, mongodb::error::Error> {
+        let sessions: Collection<Session> =
+            self.client.database("black-mesa").collection("sessions");
+
+        let options = FindOneAndUpdateOptions::builder()
+            .return_document(mongodb::options::ReturnDocument::After)
+            .build();
+
+        let res = sessions
+            .find_one_and_update(
+                doc! { "id": id, "refresh_hash": old_hash, "revoked": false },
+                doc! {
+                    "$set": {
+                        "refresh_hash": new_hash,
+                        "access_jti": access_jti,
+                        "expires_at": expires_at,
+                    },
+                    "$push": { "previous_hashes": {
+                        "$each": [old_hash],
+                        "$slice": -(PREVIOUS_REFRESH_HASHES as i64),
+                    } },
+                },
                 options,
             )
             .await?;
 
         Ok(res)
     }
+
+    pub async fn get_active_sessions(
+        &self,
+        user_id: &str,
+    ) -> Result<Vec<Session>, mongodb::error::Error> {
+        let sessions: Collection<Session> =
+            self.client.database("black-mesa").collection("sessions");
+
+        let mut res = sessions
+            .find(doc! { "user_id": user_id, "revoked": false }, None)
+            .await?;
+
+        let mut sessions = Vec::new();
+
+        while let Some(result) = res.next().await {
+            sessions.push(result?);
+        }
+
+        Ok(sessions)
+    }
+
+    pub async fn revoke_session(&self, id: &str) -> Result<(), mongodb::error::Error> {
+        let sessions: Collection<Session> =
+            self.client.database("black-mesa").collection("sessions");
+
+        sessions
+            .update_one(
+                doc! { "id": id },
+                doc! { "$set": { "revoked": true } },
+                None,
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn revoke_token(&self, token: RevokedToken) -> Result<(), mongodb::error::Error> {
+        let revoked: Collection<RevokedToken> = self
+            .client
+            .database("black-mesa")
+            .collection("revoked_tokens");
+
+        revoked.insert_one(token, None).await?;
+
+        Ok(())
+    }
+
+    pub async fn is_token_revoked(&self, jti: &str) -> Result<bool, mongodb::error::Error> {
+        let revoked: Collection<RevokedToken> = self
+            .client
+            .database("black-mesa")
+            .collection("revoked_tokens");
+
+        let res = revoked.find_one(doc! { "jti": jti }, None).await?;
+
+        Ok(res.is_some())
+    }
+
+    pub async fn create_api_key(&self, key: ApiKey) -> Result<(), mongodb::error::Error> {
+        let keys: Collection<ApiKey> = self.client.database("black-mesa").collection("api_keys");
+
+        keys.insert_one(key, None).await?;
+
+        Ok(())
+    }
+
+    pub async fn get_api_key(&self, id: &str) -> Result<Option<ApiKey>, mongodb::error::Error> {
+        let keys: Collection<ApiKey> = self.client.database("black-mesa").collection("api_keys");
+
+        let res = keys.find_one(doc! { "id": id }, None).await?;
+
+        Ok(res)
+    }
+
+    pub async fn get_api_keys(&self, owner_id: &str) -> Result<Vec<ApiKey>, mongodb::error::Error> {
+        let keys: Collection<ApiKey> = self.client.database("black-mesa").collection("api_keys");
+
+        let mut res = keys
+            .find(doc! { "owner_id": owner_id, "revoked": false }, None)
+            .await?;
+
+        let mut keys = Vec::new();
+
+        while let Some(result) = res.next().await {
+            keys.push(result?);
+        }
+
+        Ok(keys)
+    }
+
+    /// Revokes a key of `owner_id`, returning whether there was one to revoke.
+    pub async fn revoke_api_key(
+        &self,
+        id: &str,
+        owner_id: &str,
+    ) -> Result<bool, mongodb::error::Error> {
+        let keys: Collection<ApiKey> = self.client.database("black-mesa").collection("api_keys");
+
+        let res = keys
+            .update_one(
+                doc! { "id": id, "owner_id": owner_id, "revoked": false },
+                doc! { "$set": { "revoked": true } },
+                None,
+            )
+            .await?;
+
+        Ok(res.modified_count > 0)
+    }
+
+    pub async fn create_oauth_state(&self, state: OauthState) -> Result<(), mongodb::error::Error> {
+        let states: Collection<OauthState> = self
+            .client
+            .database("black-mesa")
+            .collection("oauth_states");
+
+        states.insert_one(state, None).await?;
+
+        Ok(())
+    }
+
+    /// Removes and returns an unexpired login state, so every state can only
+    /// be used once.
+    pub async fn take_oauth_state(
+        &self,
+        state: &str,
+    ) -> Result<Option<OauthState>, mongodb::error::Error> {
+        let states: Collection<OauthState> = self
+            .client
+            .database("black-mesa")
+            .collection("oauth_states");
+
+        let res = states
+            .find_one_and_delete(
+                doc! { "state": state, "expires_at": { "$gt": bson::DateTime::now() } },
+                None,
+            )
+            .await?;
+
+        Ok(res)
+    }
+
+    pub async fn get_user_flags(
+        &self,
+        user_id: &str,
+    ) -> Result<Vec<String>, mongodb::error::Error> {
+        let flags: Collection<UserFlags> =
+            self.client.database("black-mesa").collection("user_flags");
+
+        let res = flags.find_one(doc! { "user_id": user_id }, None).await?;
+
+        Ok(res.map(|res| res.flags).unwrap_or_default())
+    }
+
+    pub async fn record_staff_access(
+        &self,
+        access: StaffAccess,
+    ) -> Result<(), mongodb::error::Error> {
+        let audit: Collection<StaffAccess> =
+            self.client.database("black-mesa").collection("staff_audit");
+
+        audit.insert_one(access, None).await?;
+
+        Ok(())
+    }
 }
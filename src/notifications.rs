@@ -0,0 +1,248 @@
+use crate::{
+    crypto::TokenCipher,
+    discord::{DiscordClient, DiscordEmbed, DiscordEmbedField, DiscordMessage},
+    errors::Error,
+    mongo::Database,
+    structs::{Appeal, AppealStatus},
+    Api,
+};
+
+// Discord rejects embeds past these limits.
+const EMBED_MAX_FIELDS: usize = 25;
+const EMBED_FIELD_NAME_MAX: usize = 256;
+const EMBED_FIELD_VALUE_MAX: usize = 1024;
+/// Applies to the title and every field name and value combined.
+const EMBED_TOTAL_MAX: usize = 6000;
+
+const COLOR_PENDING: u32 = 0xf1c40f;
+const COLOR_APPROVED: u32 = 0x2ecc71;
+const COLOR_DENIED: u32 = 0xe74c3c;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AppealEvent {
+    Created,
+    Edited,
+    Decided,
+}
+
+fn truncate(value: &str, max: usize) -> String {
+    if value.chars().count() <= max {
+        return value.to_string();
+    }
+
+    let mut truncated: String = value.chars().take(max.saturating_sub(1)).collect();
+    truncated.push('…');
+    truncated
+}
+
+fn field(name: &str, value: &str, inline: bool) -> DiscordEmbedField {
+    // Discord rejects empty field names and values
+    let value = if value.trim().is_empty() { "-" } else { value };
+
+    DiscordEmbedField {
+        name: truncate(name, EMBED_FIELD_NAME_MAX),
+        value: truncate(value, EMBED_FIELD_VALUE_MAX),
+        inline,
+    }
+}
+
+/// Keeps `fields` within what is left of [`EMBED_TOTAL_MAX`] after the
+/// title. The field that crosses the limit is cut short and the rest are
+/// dropped.
+fn fit_fields(title: &str, fields: Vec<DiscordEmbedField>) -> Vec<DiscordEmbedField> {
+    let mut budget = EMBED_TOTAL_MAX - title.chars().count();
+    let mut fitted = Vec::new();
+
+    for mut field in fields.into_iter().take(EMBED_MAX_FIELDS) {
+        let name_len = field.name.chars().count();
+        let value_len = field.value.chars().count();
+
+        if name_len + value_len <= budget {
+            budget -= name_len + value_len;
+            fitted.push(field);
+            continue;
+        }
+
+        // leave room for at least a few characters of the answer
+        if budget > name_len + 1 {
+            field.value = truncate(&field.value, budget - name_len);
+            fitted.push(field);
+        }
+        break;
+    }
+
+    fitted
+}
+
+/// Builds the embed moderators see for `event` on `appeal`.
+fn appeal_embed(appeal: &Appeal, event: AppealEvent) -> DiscordEmbed {
+    let title = match (event, &appeal.status) {
+        (AppealEvent::Created, _) => "New appeal",
+        (AppealEvent::Edited, _) => "Appeal edited",
+        (AppealEvent::Decided, AppealStatus::Pending) => "Appeal reopened",
+        (AppealEvent::Decided, AppealStatus::Approved) => "Appeal approved",
+        (AppealEvent::Decided, AppealStatus::Denied) => "Appeal denied",
+    };
+
+    let color = match appeal.status {
+        AppealStatus::Pending => COLOR_PENDING,
+        AppealStatus::Approved => COLOR_APPROVED,
+        AppealStatus::Denied => COLOR_DENIED,
+    };
+
+    let mut fields = vec![
+        field("Appeal", &appeal.id, true),
+        field("User", &format!("<@{}>", appeal.user_id), true),
+        field("Punishment", &appeal.punishment_uuid, true),
+        field("Status", &appeal.status.to_string(), true),
+    ];
+
+    if let Some(reason) = &appeal.status_reason {
+        fields.push(field("Reason", reason, false));
+    }
+
+    for content in &appeal.content {
+        fields.push(field(
+            &content.question(),
+            &content.answers().join("\n"),
+            false,
+        ));
+    }
+
+    DiscordEmbed {
+        title: Some(title.to_string()),
+        description: None,
+        color: Some(color),
+        fields: fit_fields(title, fields),
+        timestamp: Some(chrono::Utc::now().to_rfc3339()),
+    }
+}
+
+/// Posts `event` on `appeal` to the appeals channel of its guild, through
+/// the guild's appeals webhook or else the bot. Guilds without appeals set up
+/// are skipped.
+async fn post_appeal_notification(
+    db: &Database,
+    discord: &DiscordClient,
+    cipher: &TokenCipher,
+    appeal: &Appeal,
+    event: AppealEvent,
+) -> Result<(), Error> {
+    let appeals = match db
+        .get_guild(&appeal.guild_id)
+        .await?
+        .and_then(|config| config.modules)
+        .and_then(|modules| modules.appeals)
+    {
+        Some(appeals) if appeals.enabled != Some(false) => appeals,
+        _ => return Ok(()),
+    };
+
+    let message = DiscordMessage {
+        embeds: vec![appeal_embed(appeal, event)],
+    };
+
+    if let Some(webhook) = db.get_appeal_webhook(&appeal.guild_id).await? {
+        let url = cipher.decrypt(&webhook.url, &appeal.guild_id)?;
+        return discord.execute_webhook(&url, &message).await;
+    }
+
+    match &appeals.channel_id {
+        Some(channel_id) if discord.has_bot_token() => {
+            discord.bot_create_message(channel_id, &message).await
+        }
+        _ => Ok(()),
+    }
+}
+
+impl Api {
+    /// Notifies moderators about `event` on `appeal` in the background, so
+    /// the request that caused it neither waits on Discord nor fails with it.
+    /// Failing to notify is logged.
+    pub fn notify_appeal(&self, appeal: &Appeal, event: AppealEvent) {
+        let db = self.db.clone();
+        let discord = self.discord.clone();
+        let cipher = self.cipher.clone();
+        let appeal = appeal.clone();
+
+        tokio::spawn(async move {
+            if let Err(e) = post_appeal_notification(&db, &discord, &cipher, &appeal, event).await {
+                tracing::warn!(
+                    "error notifying guild {} about appeal: {}",
+                    appeal.guild_id,
+                    e
+                );
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn embed_len(embed: &DiscordEmbed) -> usize {
+        embed.title.as_deref().unwrap_or("").chars().count()
+            + embed
+                .fields
+                .iter()
+                .map(|field| field.name.chars().count() + field.value.chars().count())
+                .sum::<usize>()
+    }
+
+    fn appeal(questions: usize, answer_len: usize) -> Appeal {
+        let content: Vec<_> = (0..questions)
+            .map(|i| {
+                json!({
+                    "typ": "WrittenResponse",
+                    "question": [format!("Question {}", i)],
+                    "answers": ["a".repeat(answer_len)],
+                })
+            })
+            .collect();
+
+        serde_json::from_value(json!({
+            "id": "4",
+            "guild_id": "1",
+            "user_id": "2",
+            "punishment_uuid": "3",
+            "content": content,
+            "status": "pending",
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn shows_the_punishment_and_answers() {
+        let embed = appeal_embed(&appeal(2, 10), AppealEvent::Created);
+
+        assert!(embed
+            .fields
+            .iter()
+            .any(|field| field.name == "Punishment" && field.value == "3"));
+        assert!(embed
+            .fields
+            .iter()
+            .any(|field| field.name == "Appeal" && field.value == "4"));
+        assert!(embed
+            .fields
+            .iter()
+            .any(|field| field.name == "Question 1" && field.value == "a".repeat(10)));
+    }
+
+    #[test]
+    fn stays_within_discord_limits() {
+        for (questions, answer_len) in [(5, 2000), (30, 100), (24, 1024), (1, 10_000)] {
+            let embed = appeal_embed(&appeal(questions, answer_len), AppealEvent::Edited);
+
+            assert!(embed_len(&embed) <= EMBED_TOTAL_MAX);
+            assert!(embed.fields.len() <= EMBED_MAX_FIELDS);
+            assert!(embed
+                .fields
+                .iter()
+                .all(|field| field.value.chars().count() <= EMBED_FIELD_VALUE_MAX));
+        }
+    }
+}
@@ -1,7 +1,1116 @@
-pub async fn check_permission(
-    &self,
-    guild_id: &str,
-    user_id: &str,
-) -> Result<(), hyper::Error> {
-    
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
+    str::FromStr,
+};
+
+use hyper::{Body, Request};
+use serde::Serialize;
+
+use crate::{
+    cache,
+    discord::{DiscordMember, MEMBERS_READ_SCOPE},
+    errors::Error,
+    structs::{ApiKey, Config, Group, User},
+    Api,
+};
+
+pub const CONFIG_READ: &str = "config.read";
+pub const CONFIG_WRITE: &str = "config.write";
+pub const CONFIG_DELETE: &str = "config.delete";
+pub const APPEALS_REVIEW: &str = "appeals.review";
+/// Needed on top of `config.write` to change `users`, `roles` or `groups`,
+/// since whoever can edit those can grant themselves anything.
+pub const PERMISSIONS_WRITE: &str = "permissions.write";
+
+/// Permissions the API itself checks, always included when explaining a
+/// member's permissions.
+pub const KNOWN_PERMISSIONS: &[&str] = &[
+    CONFIG_READ,
+    CONFIG_WRITE,
+    CONFIG_DELETE,
+    APPEALS_REVIEW,
+    PERMISSIONS_WRITE,
+];
+
+/// A single entry of a `permissions` list.
+///
+/// Nodes are dot separated segments of lowercase letters, digits, `_` and
+/// `-`, e.g. `moderation.ban`. The last segment may be `*`, matching every
+/// node below its prefix (`moderation.*` matches `moderation.ban`, a lone
+/// `*` matches everything). A leading `-` turns the node into a denial.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Node {
+    pub negated: bool,
+    pub segments: Vec<String>,
+    pub wildcard: bool,
+}
+
+impl FromStr for Node {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (negated, body) = match s.strip_prefix('-') {
+            Some(body) => (true, body),
+            None => (false, s),
+        };
+
+        if body.is_empty() {
+            return Err(format!("invalid permission node \"{}\": empty node", s));
+        }
+
+        let mut segments: Vec<String> = body.split('.').map(str::to_string).collect();
+        let wildcard = segments.last().map(|last| last == "*").unwrap_or(false);
+
+        if wildcard {
+            segments.pop();
+        }
+
+        for segment in &segments {
+            if segment.is_empty() {
+                return Err(format!("invalid permission node \"{}\": empty segment", s));
+            }
+
+            if segment == "*" {
+                return Err(format!(
+                    "invalid permission node \"{}\": `*` is only allowed as the last segment",
+                    s
+                ));
+            }
+
+            if !segment
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_' || c == '-')
+            {
+                return Err(format!(
+                    "invalid permission node \"{}\": segments may only contain a-z, 0-9, `_` and `-`",
+                    s
+                ));
+            }
+        }
+
+        Ok(Self {
+            negated,
+            segments,
+            wildcard,
+        })
+    }
+}
+
+impl Node {
+    /// Returns how specifically this node matches `permission`, or `None`
+    /// when it does not match at all. Exact nodes are more specific than any
+    /// wildcard, and longer wildcard prefixes beat shorter ones.
+    pub fn specificity(&self, permission: &str) -> Option<usize> {
+        let parts: Vec<&str> = permission.split('.').collect();
+
+        if self.wildcard {
+            if parts.len() > self.segments.len()
+                && self.segments.iter().zip(&parts).all(|(a, b)| a == b)
+            {
+                return Some(self.segments.len() * 2);
+            }
+            return None;
+        }
+
+        if parts.len() == self.segments.len()
+            && self.segments.iter().zip(&parts).all(|(a, b)| a == b)
+        {
+            return Some(self.segments.len() * 2 + 1);
+        }
+
+        None
+    }
+}
+
+/// Where a permission node held by a member comes from.
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Source {
+    User,
+    Role {
+        id: String,
+    },
+    /// A group reached from the user or role entries. `via` is the chain that
+    /// led to it, starting with `user` or `role:<id>` and followed by the
+    /// names of any groups it was inherited through.
+    Group {
+        name: String,
+        priority: u64,
+        via: Vec<String>,
+    },
+    /// The scope of an API key.
+    #[serde(rename = "api_key")]
+    ApiKey {
+        id: String,
+    },
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Effect {
+    Grant,
+    Deny,
+}
+
+/// A permission node together with the config entry it came from.
+#[derive(Clone, Debug, Serialize)]
+pub struct Entry {
+    pub node: String,
+    pub effect: Effect,
+    pub tier: usize,
+    pub source: Source,
+    #[serde(skip)]
+    parsed: Node,
+}
+
+/// The permission nodes held by a member of a guild, grouped into tiers
+/// ordered from the highest to the lowest precedence source.
+#[derive(Debug, Default)]
+pub struct PermissionSet {
+    tiers: Vec<Vec<Entry>>,
+}
+
+impl PermissionSet {
+    pub fn has(&self, permission: &str) -> bool {
+        self.decide(permission)
+            .map(|entry| !entry.parsed.negated)
+            .unwrap_or(false)
+    }
+
+    /// Returns the entry that decides `permission`, if any.
+    ///
+    /// Tiers are consulted in order and the first one holding any node that
+    /// matches `permission` decides. Within that tier the most specific
+    /// match wins, and a denial beats a grant of equal specificity. A
+    /// permission no tier mentions is not granted.
+    pub fn decide(&self, permission: &str) -> Option<&Entry> {
+        for tier in &self.tiers {
+            let decision = tier
+                .iter()
+                .filter_map(|entry| {
+                    entry
+                        .parsed
+                        .specificity(permission)
+                        .map(|spec| ((spec, entry.parsed.negated), entry))
+                })
+                .max_by_key(|(key, _)| *key);
+
+            if let Some((_, entry)) = decision {
+                return Some(entry);
+            }
+        }
+
+        None
+    }
+
+    /// Every entry held by the member, in precedence order.
+    pub fn entries(&self) -> impl Iterator<Item = &Entry> {
+        self.tiers.iter().flatten()
+    }
+
+    /// Evaluates the permissions the API checks, every concrete node the
+    /// member holds and any `extra` permissions, alongside the full trace of
+    /// entries they were decided from.
+    pub fn explain<'a>(
+        &'a self,
+        user_id: &'a str,
+        roles: &'a [String],
+        extra: &[String],
+    ) -> Explanation<'a> {
+        let mut checked: BTreeSet<String> =
+            KNOWN_PERMISSIONS.iter().map(|p| p.to_string()).collect();
+
+        checked.extend(
+            self.entries()
+                .filter(|entry| !entry.parsed.wildcard)
+                .map(|entry| entry.parsed.segments.join(".")),
+        );
+        checked.extend(extra.iter().cloned());
+
+        let permissions = checked
+            .into_iter()
+            .map(|permission| {
+                let decided_by = self.decide(&permission);
+                let decision = Decision {
+                    granted: decided_by
+                        .map(|entry| !entry.parsed.negated)
+                        .unwrap_or(false),
+                    decided_by,
+                };
+                (permission, decision)
+            })
+            .collect();
+
+        Explanation {
+            user_id,
+            roles,
+            permissions,
+            trace: self.entries().collect(),
+        }
+    }
+}
+
+/// The extra permissions to explain, passed as one or more comma separated
+/// `permission` query parameters.
+pub fn requested_permissions(req: &Request<Body>) -> Vec<String> {
+    url::form_urlencoded::parse(req.uri().query().unwrap_or("").as_bytes())
+        .filter(|(key, _)| key == "permission")
+        .flat_map(|(_, value)| {
+            value
+                .split(',')
+                .filter(|value| !value.is_empty())
+                .map(str::to_string)
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+#[derive(Debug, Serialize)]
+pub struct Decision<'a> {
+    pub granted: bool,
+    pub decided_by: Option<&'a Entry>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Explanation<'a> {
+    pub user_id: &'a str,
+    pub roles: &'a [String],
+    pub permissions: BTreeMap<String, Decision<'a>>,
+    pub trace: Vec<&'a Entry>,
+}
+
+fn parse_entries(permissions: &[String], tier: usize, source: &Source) -> Vec<Entry> {
+    permissions
+        .iter()
+        .filter_map(|permission| {
+            let parsed: Node = permission.parse().ok()?;
+            Some(Entry {
+                node: permission.clone(),
+                effect: if parsed.negated {
+                    Effect::Deny
+                } else {
+                    Effect::Grant
+                },
+                tier,
+                source: source.clone(),
+                parsed,
+            })
+        })
+        .collect()
+}
+
+/// Resolves the effective permissions of `user_id`, who holds the Discord
+/// roles in `roles`, against a guild config.
+///
+/// The user's direct permissions form the highest tier, followed by the
+/// combined permissions of the member's roles. Every group reachable from the
+/// user and role entries through `inherit` then forms a tier of its own,
+/// ordered by descending `priority` (ties are broken by group name so the
+/// result is stable).
+pub fn resolve(config: &Config, user_id: &str, roles: &[String]) -> PermissionSet {
+    let mut tiers = Vec::new();
+    let mut direct_groups = Vec::new();
+
+    if let Some(user) = config.users.as_ref().and_then(|users| users.get(user_id)) {
+        tiers.push(parse_entries(&user.permissions, tiers.len(), &Source::User));
+        direct_groups.extend(
+            user.groups
+                .iter()
+                .map(|name| (name.clone(), vec!["user".to_string()])),
+        );
+    }
+
+    if let Some(config_roles) = &config.roles {
+        let mut entries = Vec::new();
+        for (id, role) in roles
+            .iter()
+            .filter_map(|id| config_roles.get(id).map(|role| (id, role)))
+        {
+            let source = Source::Role { id: id.clone() };
+            entries.extend(parse_entries(&role.permissions, tiers.len(), &source));
+            direct_groups.extend(
+                role.groups
+                    .iter()
+                    .map(|name| (name.clone(), vec![format!("role:{}", id)])),
+            );
+        }
+        if !entries.is_empty() {
+            tiers.push(entries);
+        }
+    }
+
+    if let Some(groups) = &config.groups {
+        let mut seen = HashSet::new();
+        let mut queue: VecDeque<(String, Vec<String>)> = direct_groups.into();
+        let mut reached = Vec::new();
+
+        while let Some((name, via)) = queue.pop_front() {
+            if !seen.insert(name.clone()) {
+                continue;
+            }
+
+            if let Some(group) = groups.get(&name) {
+                for inherited in &group.inherit {
+                    let mut chain = via.clone();
+                    chain.push(name.clone());
+                    queue.push_back((inherited.clone(), chain));
+                }
+                reached.push((name, via, group));
+            }
+        }
+
+        reached.sort_by(|(a_name, _, a), (b_name, _, b)| {
+            b.priority.cmp(&a.priority).then_with(|| a_name.cmp(b_name))
+        });
+
+        for (name, via, group) in reached {
+            let source = Source::Group {
+                name,
+                priority: group.priority,
+                via,
+            };
+            tiers.push(parse_entries(&group.permissions, tiers.len(), &source));
+        }
+    }
+
+    PermissionSet { tiers }
+}
+
+/// The permissions an API key was scoped to in `guild_id`, as a single tier.
+pub fn resolve_scope(key: &ApiKey, guild_id: &str) -> PermissionSet {
+    let source = Source::ApiKey { id: key.id.clone() };
+
+    let entries = key
+        .scopes
+        .iter()
+        .filter(|scope| scope.guild_id == guild_id)
+        .flat_map(|scope| parse_entries(&scope.permissions, 0, &source))
+        .collect();
+
+    PermissionSet {
+        tiers: vec![entries],
+    }
+}
+
+/// Checks every `permissions` list of a config against the node grammar, and
+/// that every group reference points at an existing group without forming
+/// an inheritance cycle.
+pub fn validate_config(config: &Config) -> Result<(), String> {
+    let users = config
+        .users
+        .iter()
+        .flatten()
+        .map(|(id, user)| (format!("users.{}", id), &user.permissions));
+    let roles = config
+        .roles
+        .iter()
+        .flatten()
+        .map(|(id, role)| (format!("roles.{}", id), &role.permissions));
+    let groups = config
+        .groups
+        .iter()
+        .flatten()
+        .map(|(name, group)| (format!("groups.{}", name), &group.permissions));
+
+    for (path, permissions) in users.chain(roles).chain(groups) {
+        for permission in permissions {
+            if let Err(e) = permission.parse::<Node>() {
+                return Err(format!("{} in {}", e, path));
+            }
+        }
+    }
+
+    validate_groups(config)
+}
+
+fn validate_groups(config: &Config) -> Result<(), String> {
+    let empty = HashMap::new();
+    let groups = config.groups.as_ref().unwrap_or(&empty);
+
+    // sorted so the reported error is the same on every attempt
+    let users: BTreeMap<_, _> = config.users.iter().flatten().collect();
+    let roles: BTreeMap<_, _> = config.roles.iter().flatten().collect();
+    let names: BTreeSet<_> = groups.keys().collect();
+
+    let references = users
+        .iter()
+        .map(|(id, user)| (format!("users.{}", id), &user.groups))
+        .chain(
+            roles
+                .iter()
+                .map(|(id, role)| (format!("roles.{}", id), &role.groups)),
+        )
+        .chain(names.iter().map(|name| {
+            (
+                format!("groups.{}.inherit", name),
+                &groups[name.as_str()].inherit,
+            )
+        }));
+
+    for (path, referenced) in references {
+        if let Some(missing) = referenced.iter().find(|name| !groups.contains_key(*name)) {
+            return Err(format!("{} references missing group \"{}\"", path, missing));
+        }
+    }
+
+    let mut done = HashSet::new();
+    for name in names {
+        if let Some(cycle) = find_cycle(groups, name, &mut done) {
+            return Err(format!("group inheritance cycle: {}", cycle.join(" -> ")));
+        }
+    }
+
+    Ok(())
+}
+
+/// Depth-first walk over `inherit`, returning the offending chain when a
+/// group is reachable from itself. `done` holds the groups already known to
+/// be acyclic.
+///
+/// The walk keeps its own stack rather than recursing, so a long `inherit`
+/// chain in a submitted config can not overflow the worker's stack.
+fn find_cycle<'a>(
+    groups: &'a HashMap<String, Group>,
+    name: &'a str,
+    done: &mut HashSet<&'a str>,
+) -> Option<Vec<String>> {
+    if done.contains(name) {
+        return None;
+    }
+
+    // the groups on the current path, each with the index of the next
+    // inherited group to visit
+    let mut stack: Vec<(&'a str, usize)> = vec![(name, 0)];
+    let mut on_stack: HashSet<&'a str> = HashSet::from([name]);
+
+    while let Some((current, next)) = stack.last_mut() {
+        let current = *current;
+
+        let inherited = match groups
+            .get(current)
+            .and_then(|group| group.inherit.get(*next))
+        {
+            Some(inherited) => inherited.as_str(),
+            None => {
+                stack.pop();
+                on_stack.remove(current);
+                done.insert(current);
+                continue;
+            }
+        };
+        *next += 1;
+
+        if done.contains(inherited) {
+            continue;
+        }
+
+        if on_stack.contains(inherited) {
+            let start = stack.iter().position(|(entry, _)| *entry == inherited)?;
+            let mut cycle: Vec<String> = stack[start..]
+                .iter()
+                .map(|(entry, _)| entry.to_string())
+                .collect();
+            cycle.push(inherited.to_string());
+            return Some(cycle);
+        }
+
+        on_stack.insert(inherited);
+        stack.push((inherited, 0));
+    }
+
+    None
+}
+
+/// Whether `new` grants anything differently from `old`, i.e. whether its
+/// `users`, `roles` or `groups` changed. Missing and empty maps are the same.
+pub fn permissions_changed(old: Option<&Config>, new: &Config) -> bool {
+    fn non_empty<T>(map: &Option<HashMap<String, T>>) -> Option<&HashMap<String, T>> {
+        map.as_ref().filter(|map| !map.is_empty())
+    }
+
+    match old {
+        Some(old) => {
+            non_empty(&old.users) != non_empty(&new.users)
+                || non_empty(&old.roles) != non_empty(&new.roles)
+                || non_empty(&old.groups) != non_empty(&new.groups)
+        }
+        None => {
+            non_empty(&new.users).is_some()
+                || non_empty(&new.roles).is_some()
+                || non_empty(&new.groups).is_some()
+        }
+    }
+}
+
+/// Grants `user_id` full control over the config they are bootstrapping, so
+/// the guild owner cannot lock themselves out of a freshly created config.
+pub fn grant_bootstrap(config: &mut Config, user_id: &str) {
+    let user = config
+        .users
+        .get_or_insert_with(Default::default)
+        .entry(user_id.to_string())
+        .or_insert_with(|| User {
+            groups: Vec::new(),
+            permissions: Vec::new(),
+        });
+
+    for permission in [CONFIG_READ, CONFIG_WRITE, CONFIG_DELETE, PERMISSIONS_WRITE] {
+        if !user.permissions.iter().any(|p| p == permission) {
+            user.permissions.push(permission.to_string());
+        }
+    }
+}
+
+/// Whether the roles of `user_id` could change if `permission` is granted,
+/// i.e. whether they have to be looked up at all. They can not when the
+/// config has no role entries, or when the user's own entry already decides
+/// it, as that tier outranks every role.
+pub fn roles_matter(config: &Config, user_id: &str, permission: &str) -> bool {
+    if config.roles.as_ref().is_none_or(|roles| roles.is_empty()) {
+        return false;
+    }
+
+    !matches!(
+        resolve(config, user_id, &[]).decide(permission),
+        Some(Entry {
+            source: Source::User,
+            ..
+        })
+    )
+}
+
+/// The roles of `member`, including `@everyone`, whose ID is the guild ID.
+/// Non-members hold no roles at all.
+fn with_everyone(guild_id: &str, member: Option<DiscordMember>) -> Vec<String> {
+    match member {
+        Some(member) => {
+            let mut roles = member.roles;
+            if !roles.iter().any(|role| role == guild_id) {
+                roles.push(guild_id.to_string());
+            }
+            roles
+        }
+        None => Vec::new(),
+    }
+}
+
+impl Api {
+    /// Returns the Discord role IDs `user_id` holds in `guild_id`, from the
+    /// cache when possible. Without a bot this acts through the user's own
+    /// Discord token, so `user_id` has to be the caller, or the owner of the
+    /// API key making the request.
+    pub async fn member_roles(&self, guild_id: &str, user_id: &str) -> Result<Vec<String>, Error> {
+        let key = cache::member_roles_key(guild_id, user_id);
+
+        if let Some(roles) = self.cache.get(&key).await {
+            return Ok(roles);
+        }
+
+        let roles = self.fetch_member_roles(guild_id, user_id).await?;
+        self.cache.set(&key, &roles, cache::MEMBER_ROLES_TTL).await;
+
+        Ok(roles)
+    }
+
+    /// Like [`Api::member_roles`], for a member other than the caller. Only
+    /// the bot can look them up, their own token is never used on a request
+    /// they did not make, so without a bot they hold no roles.
+    pub async fn other_member_roles(
+        &self,
+        guild_id: &str,
+        user_id: &str,
+    ) -> Result<Vec<String>, Error> {
+        let key = cache::member_roles_key(guild_id, user_id);
+
+        if let Some(roles) = self.cache.get(&key).await {
+            return Ok(roles);
+        }
+
+        if !self.discord.has_bot_token() {
+            // not cached, the member's own lookup may still see their roles
+            return Ok(Vec::new());
+        }
+
+        let member = self.discord.bot_member(guild_id, user_id).await?;
+        let roles = with_everyone(guild_id, member);
+        self.cache.set(&key, &roles, cache::MEMBER_ROLES_TTL).await;
+
+        Ok(roles)
+    }
+
+    /// Asks Discord for the roles of a member, through the bot when it is
+    /// configured, and otherwise through the user's own token if it was
+    /// granted `guilds.members.read`. Users that are not in the guild, or
+    /// whose roles can not be seen, hold no roles.
+    ///
+    /// Discord leaves `@everyone` out of a member's roles, so it is added
+    /// for every member to let config entries for it apply.
+    async fn fetch_member_roles(
+        &self,
+        guild_id: &str,
+        user_id: &str,
+    ) -> Result<Vec<String>, Error> {
+        if self.discord.has_bot_token() {
+            let member = self.discord.bot_member(guild_id, user_id).await?;
+
+            return Ok(with_everyone(guild_id, member));
+        }
+
+        let can_read_members = match self.db.get_discord_token(user_id).await? {
+            Some(token) => token
+                .scope
+                .split(' ')
+                .any(|scope| scope == MEMBERS_READ_SCOPE),
+            None => false,
+        };
+
+        if !can_read_members {
+            return Ok(Vec::new());
+        }
+
+        let token = match self.discord_access_token(user_id).await? {
+            Some(token) => token,
+            None => return Ok(Vec::new()),
+        };
+
+        let member = self.discord.user_member(&token, guild_id).await?;
+
+        Ok(with_everyone(guild_id, member))
+    }
+
+    /// Whether `user_id` holds `permission` under an already loaded `config`
+    /// of `guild_id`. Their roles are only looked up when they could change
+    /// the answer.
+    pub async fn member_has_permission(
+        &self,
+        guild_id: &str,
+        user_id: &str,
+        config: &Config,
+        permission: &str,
+    ) -> Result<bool, Error> {
+        let roles = if roles_matter(config, user_id, permission) {
+            self.member_roles(guild_id, user_id).await?
+        } else {
+            Vec::new()
+        };
+
+        Ok(resolve(config, user_id, &roles).has(permission))
+    }
+
+    pub async fn check_permission(
+        &self,
+        guild_id: &str,
+        user_id: &str,
+        permission: &str,
+    ) -> Result<bool, Error> {
+        let config = match self.db.get_guild(guild_id).await? {
+            Some(config) => config,
+            None => return Ok(false),
+        };
+
+        self.member_has_permission(guild_id, user_id, &config, permission)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn config(value: serde_json::Value) -> Config {
+        serde_json::from_value(value).unwrap()
+    }
+
+    fn user_config(permissions: &[&str]) -> Config {
+        config(json!({
+            "users": { "1": { "groups": [], "permissions": permissions } }
+        }))
+    }
+
+    #[test]
+    fn grants_from_user_roles_and_inherited_groups() {
+        let config = config(json!({
+            "users": { "1": { "groups": ["mods"], "permissions": ["config.read"] } },
+            "roles": { "10": { "groups": [], "permissions": ["appeals.review"] } },
+            "groups": {
+                "mods": { "permissions": [], "inherit": ["base"], "priority": 1 },
+                "base": { "permissions": ["moderation.ban"], "inherit": [], "priority": 0 }
+            }
+        }));
+        let set = resolve(&config, "1", &["10".to_string()]);
+
+        assert!(set.has("config.read"));
+        assert!(set.has("appeals.review"));
+        assert!(set.has("moderation.ban"));
+        assert!(!set.has("config.write"));
+    }
+
+    #[test]
+    fn unknown_users_and_roles_get_nothing() {
+        let config = config(json!({
+            "users": { "1": { "groups": [], "permissions": ["config.read"] } },
+            "roles": { "10": { "groups": [], "permissions": ["config.read"] } }
+        }));
+        let set = resolve(&config, "2", &["11".to_string()]);
+
+        assert!(!set.has("config.read"));
+    }
+
+    #[test]
+    fn inheritance_cycles_terminate() {
+        let config = config(json!({
+            "users": { "1": { "groups": ["a"], "permissions": [] } },
+            "groups": {
+                "a": { "permissions": ["config.read"], "inherit": ["b"], "priority": 0 },
+                "b": { "permissions": ["config.write"], "inherit": ["a"], "priority": 0 }
+            }
+        }));
+        let set = resolve(&config, "1", &[]);
+
+        assert!(set.has("config.read"));
+        assert!(set.has("config.write"));
+    }
+
+    #[test]
+    fn decodes_requested_permissions() {
+        let req = Request::builder()
+            .uri("/api/v1/guild/1/permissions/2?permission=a.b%2Cc.d&refresh=true&permission=e")
+            .body(Body::empty())
+            .unwrap();
+
+        assert_eq!(requested_permissions(&req), ["a.b", "c.d", "e"]);
+    }
+
+    #[test]
+    fn roles_only_matter_below_a_deciding_user_entry() {
+        assert!(!roles_matter(
+            &user_config(&["config.read"]),
+            "1",
+            CONFIG_WRITE
+        ));
+
+        let config = config(json!({
+            "users": { "1": { "groups": [], "permissions": ["-config.*"] } },
+            "roles": { "10": { "groups": [], "permissions": ["config.write"] } }
+        }));
+        assert!(!roles_matter(&config, "1", CONFIG_WRITE));
+        assert!(roles_matter(&config, "1", APPEALS_REVIEW));
+        assert!(roles_matter(&config, "2", CONFIG_WRITE));
+    }
+
+    #[test]
+    fn parses_nodes() {
+        let node: Node = "moderation.ban".parse().unwrap();
+        assert!(!node.negated && !node.wildcard);
+        assert_eq!(node.segments, ["moderation", "ban"]);
+
+        let node: Node = "-moderation.*".parse().unwrap();
+        assert!(node.negated && node.wildcard);
+        assert_eq!(node.segments, ["moderation"]);
+
+        let node: Node = "*".parse().unwrap();
+        assert!(node.wildcard && node.segments.is_empty());
+
+        assert!("snake_case.kebab-case.v2".parse::<Node>().is_ok());
+    }
+
+    #[test]
+    fn rejects_invalid_nodes() {
+        for node in [
+            "",
+            "-",
+            "a..b",
+            ".a",
+            "a.",
+            "a.*.b",
+            "*.a",
+            "**",
+            "Config.read",
+            "a b",
+            "a.b!",
+        ] {
+            assert!(
+                node.parse::<Node>().is_err(),
+                "{:?} should be rejected",
+                node
+            );
+        }
+
+        assert_eq!(
+            "a.*.b".parse::<Node>().unwrap_err(),
+            "invalid permission node \"a.*.b\": `*` is only allowed as the last segment"
+        );
+    }
+
+    #[test]
+    fn wildcards_match_below_their_prefix() {
+        let node: Node = "moderation.*".parse().unwrap();
+        assert!(node.specificity("moderation.ban").is_some());
+        assert!(node.specificity("moderation.ban.temp").is_some());
+        assert!(node.specificity("moderation").is_none());
+        assert!(node.specificity("moderationx.ban").is_none());
+
+        let all: Node = "*".parse().unwrap();
+        assert!(all.specificity("config.read").is_some());
+    }
+
+    #[test]
+    fn exact_beats_wildcard_and_longer_prefix_beats_shorter() {
+        let exact: Node = "a.b.c".parse().unwrap();
+        let long: Node = "a.b.*".parse().unwrap();
+        let short: Node = "a.*".parse().unwrap();
+        let all: Node = "*".parse().unwrap();
+
+        let spec = |node: &Node| node.specificity("a.b.c").unwrap();
+        assert!(spec(&exact) > spec(&long));
+        assert!(spec(&long) > spec(&short));
+        assert!(spec(&short) > spec(&all));
+    }
+
+    #[test]
+    fn most_specific_node_in_a_tier_decides() {
+        let set = resolve(&user_config(&["moderation.*", "-moderation.ban"]), "1", &[]);
+        assert!(set.has("moderation.kick"));
+        assert!(!set.has("moderation.ban"));
+
+        let set = resolve(&user_config(&["-moderation.*", "moderation.ban"]), "1", &[]);
+        assert!(!set.has("moderation.kick"));
+        assert!(set.has("moderation.ban"));
+    }
+
+    #[test]
+    fn denial_wins_ties() {
+        let set = resolve(&user_config(&["config.read", "-config.read"]), "1", &[]);
+        assert!(!set.has("config.read"));
+
+        let set = resolve(&user_config(&["-config.*", "config.*"]), "1", &[]);
+        assert!(!set.has("config.write"));
+    }
+
+    #[test]
+    fn unmentioned_permissions_are_not_granted() {
+        let set = resolve(&user_config(&["config.read"]), "1", &[]);
+        assert!(!set.has("config.write"));
+        assert!(set.decide("config.write").is_none());
+
+        let set = resolve(&user_config(&["config.read"]), "2", &[]);
+        assert!(!set.has("config.read"));
+    }
+
+    #[test]
+    fn higher_tiers_decide_before_more_specific_lower_ones() {
+        let config = config(json!({
+            "users": { "1": { "groups": ["mods"], "permissions": ["-config.*"] } },
+            "roles": { "10": { "groups": [], "permissions": ["config.write", "-appeals.review"] } },
+            "groups": {
+                "mods": { "permissions": ["*"], "inherit": [], "priority": 0 }
+            }
+        }));
+        let set = resolve(&config, "1", &["10".to_string()]);
+
+        // the user tier denies config.*, however specific the role grant is
+        assert!(!set.has("config.write"));
+        // the role tier denies appeals.review before the group grants *
+        assert!(!set.has("appeals.review"));
+        // only the group mentions moderation.ban
+        assert!(set.has("moderation.ban"));
+
+        let decided_by = set.decide("config.write").unwrap();
+        assert_eq!(decided_by.tier, 0);
+        assert!(matches!(decided_by.source, Source::User));
+    }
+
+    #[test]
+    fn groups_are_ordered_by_priority_then_name() {
+        let config = config(json!({
+            "users": { "1": { "groups": ["low", "high", "b", "a"], "permissions": [] } },
+            "groups": {
+                "low": { "permissions": ["-x"], "inherit": [], "priority": 1 },
+                "high": { "permissions": ["x"], "inherit": [], "priority": 5 },
+                "a": { "permissions": ["y"], "inherit": [], "priority": 0 },
+                "b": { "permissions": ["-y"], "inherit": [], "priority": 0 }
+            }
+        }));
+        let set = resolve(&config, "1", &[]);
+
+        assert!(set.has("x"));
+        assert!(set.has("y"));
+    }
+
+    #[test]
+    fn members_hold_everyone() {
+        let member = DiscordMember {
+            roles: vec!["10".to_string()],
+        };
+        let roles = with_everyone("100", Some(member));
+        assert_eq!(roles, ["10", "100"]);
+        assert!(with_everyone("100", None).is_empty());
+
+        let config = config(json!({
+            "roles": { "100": { "groups": [], "permissions": ["appeals.review"] } }
+        }));
+        assert!(resolve(&config, "1", &roles).has("appeals.review"));
+    }
+
+    fn groups_config(groups: serde_json::Value) -> Config {
+        config(json!({ "groups": groups }))
+    }
+
+    #[test]
+    fn accepts_valid_groups() {
+        // a diamond reaches `base` twice without being a cycle
+        let config = config(json!({
+            "users": { "1": { "groups": ["admins"], "permissions": ["config.*"] } },
+            "roles": { "10": { "groups": ["mods"], "permissions": [] } },
+            "groups": {
+                "admins": { "permissions": [], "inherit": ["mods", "support"], "priority": 2 },
+                "mods": { "permissions": [], "inherit": ["base"], "priority": 1 },
+                "support": { "permissions": [], "inherit": ["base"], "priority": 1 },
+                "base": { "permissions": ["appeals.review"], "inherit": [], "priority": 0 }
+            }
+        }));
+
+        assert_eq!(validate_config(&config), Ok(()));
+    }
+
+    #[test]
+    fn rejects_self_inheritance() {
+        let config = groups_config(json!({
+            "a": { "permissions": [], "inherit": ["a"], "priority": 0 }
+        }));
+
+        assert_eq!(
+            validate_config(&config),
+            Err("group inheritance cycle: a -> a".to_string())
+        );
+    }
+
+    fn chain(len: usize) -> HashMap<String, Group> {
+        (0..len)
+            .map(|i| {
+                let inherit = if i + 1 < len {
+                    vec![format!("g{}", i + 1)]
+                } else {
+                    Vec::new()
+                };
+                let group = Group {
+                    permissions: Vec::new(),
+                    inherit,
+                    priority: 0,
+                };
+                (format!("g{}", i), group)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn validates_long_inheritance_chains() {
+        let mut config = config(json!({}));
+        config.groups = Some(chain(100_000));
+        assert_eq!(validate_config(&config), Ok(()));
+
+        // closing the chain into a loop is still found
+        let mut groups = chain(100_000);
+        groups
+            .get_mut("g99999")
+            .unwrap()
+            .inherit
+            .push("g0".to_string());
+        config.groups = Some(groups);
+        let err = validate_config(&config).unwrap_err();
+        assert!(err.starts_with("group inheritance cycle: g0 -> g1 -> "));
+        assert!(err.ends_with(" -> g99999 -> g0"));
+    }
+
+    #[test]
+    fn rejects_longer_cycles() {
+        let config = groups_config(json!({
+            "c": { "permissions": [], "inherit": ["a"], "priority": 0 },
+            "b": { "permissions": [], "inherit": ["c"], "priority": 0 },
+            "a": { "permissions": [], "inherit": ["b"], "priority": 0 },
+            "d": { "permissions": [], "inherit": ["a"], "priority": 0 }
+        }));
+
+        assert_eq!(
+            validate_config(&config),
+            Err("group inheritance cycle: a -> b -> c -> a".to_string())
+        );
+    }
+
+    #[test]
+    fn rejects_dangling_group_references() {
+        let user = config(json!({
+            "users": { "1": { "groups": ["ghost"], "permissions": [] } }
+        }));
+        assert_eq!(
+            validate_config(&user),
+            Err("users.1 references missing group \"ghost\"".to_string())
+        );
+
+        let role = config(json!({
+            "roles": { "10": { "groups": ["ghost"], "permissions": [] } }
+        }));
+        assert_eq!(
+            validate_config(&role),
+            Err("roles.10 references missing group \"ghost\"".to_string())
+        );
+
+        let inherit = groups_config(json!({
+            "mods": { "permissions": [], "inherit": ["ghost"], "priority": 0 }
+        }));
+        assert_eq!(
+            validate_config(&inherit),
+            Err("groups.mods.inherit references missing group \"ghost\"".to_string())
+        );
+    }
+
+    #[test]
+    fn reports_the_same_error_every_time() {
+        // every map gets its own hash seed, so each attempt iterates differently
+        for _ in 0..10 {
+            let config = config(json!({
+                "users": {
+                    "3": { "groups": ["ghost-c"], "permissions": [] },
+                    "1": { "groups": ["ghost-a"], "permissions": [] },
+                    "2": { "groups": ["ghost-b"], "permissions": [] }
+                },
+                "roles": { "10": { "groups": ["ghost-d"], "permissions": [] } },
+                "groups": {
+                    "y": { "permissions": [], "inherit": ["x"], "priority": 0 },
+                    "x": { "permissions": [], "inherit": ["y"], "priority": 0 }
+                }
+            }));
+
+            assert_eq!(
+                validate_config(&config),
+                Err("users.1 references missing group \"ghost-a\"".to_string())
+            );
+        }
+
+        for _ in 0..10 {
+            let config = groups_config(json!({
+                "y": { "permissions": [], "inherit": ["x"], "priority": 0 },
+                "x": { "permissions": [], "inherit": ["y"], "priority": 0 }
+            }));
+
+            assert_eq!(
+                validate_config(&config),
+                Err("group inheritance cycle: x -> y -> x".to_string())
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_nodes_with_their_path() {
+        let config = config(json!({
+            "roles": { "10": { "groups": [], "permissions": ["config.read", "Config.Write"] } }
+        }));
+
+        assert_eq!(
+            validate_config(&config),
+            Err("invalid permission node \"Config.Write\": segments may only contain a-z, 0-9, `_` and `-` in roles.10".to_string())
+        );
+    }
 }
@@ -0,0 +1,322 @@
+use std::collections::HashSet;
+
+use hyper::{Body, Request, Response};
+use serde::Serialize;
+use serde_with::skip_serializing_none;
+
+use crate::{auth::Principal, errors::Error, permissions::CONFIG_READ, structs::Config, Api};
+
+/// The response to a config write: the saved config, along with the
+/// channel and role IDs in it that do not exist in the guild. The list is
+/// left out when the guild could not be checked, e.g. without the bot.
+#[skip_serializing_none]
+#[derive(Debug, Serialize)]
+pub struct ConfigWriteResponse {
+    #[serde(flatten)]
+    pub config: Config,
+    pub dangling_references: Option<Vec<DanglingReference>>,
+}
+
+/// Whether a config write asked for `strict=true`, rejecting dangling
+/// references instead of saving them and reporting them.
+pub fn strict_requested(req: &Request<Body>) -> bool {
+    url::form_urlencoded::parse(req.uri().query().unwrap_or("").as_bytes())
+        .any(|(key, value)| key == "strict" && value == "true")
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReferenceKind {
+    Channel,
+    Role,
+}
+
+/// A snowflake in a config that does not exist in the guild.
+#[derive(Debug, Serialize)]
+pub struct DanglingReference {
+    /// Where in the config the ID is used, e.g. `modules.logging.channel_id`.
+    pub path: String,
+    pub id: String,
+    pub kind: ReferenceKind,
+}
+
+fn push_channels<'a>(
+    refs: &mut Vec<(String, &'a str, ReferenceKind)>,
+    path: String,
+    ids: &'a [String],
+) {
+    for id in ids {
+        refs.push((path.clone(), id, ReferenceKind::Channel));
+    }
+}
+
+/// Every channel and role ID `config` refers to, with where it is used.
+fn references(config: &Config) -> Vec<(String, &str, ReferenceKind)> {
+    let mut refs = Vec::new();
+
+    if let Some(roles) = &config.roles {
+        for id in roles.keys() {
+            refs.push(("roles".to_string(), id.as_str(), ReferenceKind::Role));
+        }
+    }
+
+    let modules = match &config.modules {
+        Some(modules) => modules,
+        None => return refs,
+    };
+
+    if let Some(logging) = &modules.logging {
+        if let Some(id) = &logging.channel_id {
+            refs.push((
+                "modules.logging.channel_id".to_string(),
+                id,
+                ReferenceKind::Channel,
+            ));
+        }
+
+        if let Some(ids) = &logging.ignored_channels {
+            push_channels(
+                &mut refs,
+                "modules.logging.ignored_channels".to_string(),
+                ids,
+            );
+        }
+    }
+
+    if let Some(id) = modules
+        .appeals
+        .as_ref()
+        .and_then(|appeals| appeals.channel_id.as_ref())
+    {
+        refs.push((
+            "modules.appeals.channel_id".to_string(),
+            id,
+            ReferenceKind::Channel,
+        ));
+    }
+
+    if let Some(id) = modules
+        .moderation
+        .as_ref()
+        .and_then(|moderation| moderation.mute_role.as_ref())
+    {
+        refs.push((
+            "modules.moderation.mute_role".to_string(),
+            id,
+            ReferenceKind::Role,
+        ));
+    }
+
+    if let Some(automod) = &modules.automod {
+        for (i, censor) in automod.censor.iter().flatten().enumerate() {
+            push_channels(
+                &mut refs,
+                format!("modules.automod.censor[{}].monitor_channels", i),
+                &censor.monitor_channels,
+            );
+            push_channels(
+                &mut refs,
+                format!("modules.automod.censor[{}].ignore_channels", i),
+                &censor.ignore_channels,
+            );
+        }
+
+        for (i, spam) in automod.spam.iter().flatten().enumerate() {
+            push_channels(
+                &mut refs,
+                format!("modules.automod.spam[{}].monitor_channels", i),
+                &spam.monitor_channels,
+            );
+            push_channels(
+                &mut refs,
+                format!("modules.automod.spam[{}].ignore_channels", i),
+                &spam.ignore_channels,
+            );
+        }
+    }
+
+    refs
+}
+
+impl Api {
+    /// Resolves the channel and role IDs of `config` against `guild_id`
+    /// through the bot, returning the ones that do not exist. Returns `None`
+    /// when the bot is not in the guild, so nothing can be checked.
+    pub async fn dangling_references(
+        &self,
+        guild_id: &str,
+        config: &Config,
+    ) -> Result<Option<Vec<DanglingReference>>, Error> {
+        let refs = references(config);
+
+        if refs.is_empty() {
+            return Ok(Some(Vec::new()));
+        }
+
+        let channels = match self.guild_channels(guild_id).await? {
+            Some(channels) => channels,
+            None => return Ok(None),
+        };
+
+        let roles = match self.guild_roles(guild_id).await? {
+            Some(roles) => roles,
+            None => return Ok(None),
+        };
+
+        let channel_ids: HashSet<&str> = channels.iter().map(|c| c.id.as_str()).collect();
+        let role_ids: HashSet<&str> = roles.iter().map(|r| r.id.as_str()).collect();
+
+        Ok(Some(
+            refs.into_iter()
+                .filter(|(_, id, kind)| match kind {
+                    ReferenceKind::Channel => !channel_ids.contains(id),
+                    ReferenceKind::Role => !role_ids.contains(id),
+                })
+                .map(|(path, id, kind)| DanglingReference {
+                    path,
+                    id: id.to_string(),
+                    kind,
+                })
+                .collect(),
+        ))
+    }
+
+    /// Resolves the references of a config about to be written. Returns a
+    /// response to send instead when a strict write has to be rejected, and
+    /// otherwise the dangling references to report with the saved config,
+    /// `None` when they could not be checked.
+    pub async fn check_write_references(
+        &self,
+        guild_id: &str,
+        config: &Config,
+        strict: bool,
+    ) -> Result<(Option<Response<Body>>, Option<Vec<DanglingReference>>), hyper::Error> {
+        let dangling = match self.dangling_references(guild_id, config).await {
+            Ok(dangling) => dangling,
+            Err(e) if strict => {
+                tracing::error!("error validating references: {}", e);
+                return Ok((Some(self.discord_error(&e).await?), None));
+            }
+            Err(e) => {
+                // a lenient write does not depend on Discord being reachable
+                tracing::warn!("error validating references: {}", e);
+                None
+            }
+        };
+
+        if !strict {
+            return Ok((None, dangling));
+        }
+
+        let dangling = match dangling {
+            Some(dangling) if dangling.is_empty() => return Ok((None, Some(dangling))),
+            Some(dangling) => dangling,
+            None => {
+                return Ok((
+                    Some(
+                        self.bad_request(
+                            "the bot is not in this guild, references can not be validated",
+                        )
+                        .await?,
+                    ),
+                    None,
+                ))
+            }
+        };
+
+        let json = match serde_json::to_string(&dangling) {
+            Ok(json) => json,
+            Err(_) => return Ok((Some(self.internal_server_error().await?), None)),
+        };
+
+        Ok((
+            Some(
+                Response::builder()
+                    .status(400)
+                    .header("Content-Type", "application/json")
+                    .body(Body::from(json))
+                    .unwrap(),
+            ),
+            None,
+        ))
+    }
+
+    /// Lists the references of the stored config that do not exist in the
+    /// guild, e.g. channels deleted since the config was written.
+    pub async fn get_guild_references(
+        &self,
+        req: Request<Body>,
+        guild_id: &str,
+        principal: &Principal,
+    ) -> Result<Response<Body>, hyper::Error> {
+        if let Some(resp) = self
+            .authorize_guild(principal, guild_id, CONFIG_READ)
+            .await?
+        {
+            return Ok(resp);
+        }
+
+        let config = match self.db.get_guild(guild_id).await {
+            Ok(Some(config)) => config,
+            Ok(None) => return self.not_found().await,
+            Err(e) => {
+                tracing::error!("error getting guild: {}", e);
+                return self.internal_server_error().await;
+            }
+        };
+
+        match self.dangling_references(guild_id, &config).await {
+            Ok(Some(dangling)) => self.json_response(req, dangling).await,
+            Ok(None) => self.not_found().await,
+            Err(e) => {
+                tracing::error!("error validating references: {}", e);
+                self.discord_error(&e).await
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn walks_every_channel_and_role_list() {
+        let config: Config = serde_json::from_value(json!({
+            "roles": { "1": { "groups": [], "permissions": [] } },
+            "modules": {
+                "logging": { "channel_id": "2", "ignored_channels": ["3"] },
+                "appeals": { "channel_id": "4" },
+                "automod": {
+                    "censor": [{ "bypass": [], "monitor_channels": ["5"], "ignore_channels": ["6"] }],
+                    "spam": [{ "bypass": [], "monitor_channels": ["7"], "ignore_channels": ["8"] }]
+                }
+            }
+        }))
+        .unwrap();
+
+        let refs: Vec<(String, &str)> = references(&config)
+            .into_iter()
+            .map(|(path, id, _)| (path, id))
+            .collect();
+
+        assert_eq!(
+            refs,
+            [
+                ("roles".to_string(), "1"),
+                ("modules.logging.channel_id".to_string(), "2"),
+                ("modules.logging.ignored_channels".to_string(), "3"),
+                ("modules.appeals.channel_id".to_string(), "4"),
+                (
+                    "modules.automod.censor[0].monitor_channels".to_string(),
+                    "5"
+                ),
+                ("modules.automod.censor[0].ignore_channels".to_string(), "6"),
+                ("modules.automod.spam[0].monitor_channels".to_string(), "7"),
+                ("modules.automod.spam[0].ignore_channels".to_string(), "8"),
+            ]
+        );
+    }
+}
@@ -0,0 +1,301 @@
+use hyper::{header::HeaderValue, Body, Response};
+
+use crate::{
+    auth::{Claims, ACCESS_TOKEN_TTL},
+    crypto,
+    discord::DiscordUser,
+    errors::Error,
+    oauth::OauthResponse,
+    structs::{RevokedToken, Session},
+    Api,
+};
+
+/// How long a refresh token can go unused before its session expires. Every
+/// refresh hands out a new refresh token and restarts this window.
+pub const REFRESH_TOKEN_TTL: i64 = 30 * 24 * 60 * 60;
+
+/// How many rotated-out refresh tokens a session remembers to detect reuse.
+pub const PREVIOUS_REFRESH_HASHES: usize = 16;
+
+/// Holds the access token of a cookie session.
+pub const SESSION_COOKIE: &str = "bm_session";
+/// Holds the refresh token of a cookie session, only sent to `/oauth`.
+pub const REFRESH_COOKIE: &str = "bm_refresh";
+/// Double-submit CSRF token, state-changing requests authenticated by cookie
+/// have to echo it in [`CSRF_HEADER`].
+pub const CSRF_COOKIE: &str = "bm_csrf";
+pub const CSRF_HEADER: &str = "X-CSRF-Token";
+
+/// Builds the `Set-Cookie` values that store `session` in the browser.
+pub fn session_cookies(session: &OauthResponse, csrf_token: &str) -> Vec<String> {
+    let now = chrono::Utc::now().timestamp();
+
+    vec![
+        format!(
+            "{}={}; Path=/; Max-Age={}; HttpOnly; Secure; SameSite=Strict",
+            SESSION_COOKIE,
+            session.token,
+            session.expires as i64 - now
+        ),
+        format!(
+            "{}={}; Path=/oauth; Max-Age={}; HttpOnly; Secure; SameSite=Strict",
+            REFRESH_COOKIE,
+            session.refresh_token,
+            session.refresh_expires - now
+        ),
+        format!(
+            "{}={}; Path=/; Max-Age={}; Secure; SameSite=Strict",
+            CSRF_COOKIE,
+            csrf_token,
+            session.refresh_expires - now
+        ),
+    ]
+}
+
+/// Builds the `Set-Cookie` values that remove a cookie session.
+pub fn clear_session_cookies() -> Vec<String> {
+    vec![
+        format!("{}=; Path=/; Max-Age=0", SESSION_COOKIE),
+        format!("{}=; Path=/oauth; Max-Age=0", REFRESH_COOKIE),
+        format!("{}=; Path=/; Max-Age=0", CSRF_COOKIE),
+    ]
+}
+
+pub fn append_cookies(resp: &mut Response<Body>, cookies: Vec<String>) {
+    for cookie in cookies {
+        // cookie values are base64url and JWTs, which are always valid header values
+        resp.headers_mut()
+            .append("Set-Cookie", HeaderValue::from_str(&cookie).unwrap());
+    }
+}
+
+fn timestamp(secs: i64) -> bson::DateTime {
+    bson::DateTime::from_millis(secs * 1000)
+}
+
+/// Refresh tokens are `<session id>.<secret>`, only the secret is hashed.
+fn split_refresh_token(refresh_token: &str) -> Option<(&str, &str)> {
+    refresh_token
+        .split_once('.')
+        .filter(|(id, secret)| !id.is_empty() && !secret.is_empty())
+}
+
+#[derive(Debug, PartialEq)]
+enum RefreshHash {
+    Current,
+    /// A refresh token the session already rotated out.
+    Reused,
+    Unknown,
+}
+
+/// The session ID is no secret, it is the `sid` claim of every access token,
+/// so only a hash the session actually handed out counts as reuse.
+fn check_refresh_hash(session: &Session, hash: &str) -> RefreshHash {
+    if session.refresh_hash == hash {
+        RefreshHash::Current
+    } else if session
+        .previous_hashes
+        .iter()
+        .any(|previous| previous == hash)
+    {
+        RefreshHash::Reused
+    } else {
+        RefreshHash::Unknown
+    }
+}
+
+impl Api {
+    pub async fn create_session(&self, user: &DiscordUser) -> Result<OauthResponse, Error> {
+        let id = crypto::random_token(16);
+        let secret = crypto::random_token(32);
+        let staff = self.is_staff(&user.id).await?;
+        let (token, claims) = self.jwt.create_jwt(user, &id, staff)?;
+
+        let now = chrono::Utc::now().timestamp();
+        let refresh_expires = now + REFRESH_TOKEN_TTL;
+
+        self.db
+            .create_session(Session {
+                id: id.clone(),
+                user_id: user.id.clone(),
+                refresh_hash: crypto::hash_token(&secret),
+                previous_hashes: Vec::new(),
+                access_jti: claims.jti,
+                created_at: timestamp(now),
+                expires_at: timestamp(refresh_expires),
+                revoked: false,
+            })
+            .await?;
+
+        Ok(OauthResponse {
+            token,
+            expires: claims.exp,
+            refresh_token: format!("{}.{}", id, secret),
+            refresh_expires,
+        })
+    }
+
+    /// Redeems a refresh token for a new access and refresh token pair,
+    /// revoking the access token it replaces. Returns `None` when the refresh
+    /// token is invalid, expired or revoked.
+    ///
+    /// Presenting a refresh token that was already rotated out means it leaked,
+    /// so the whole session is revoked. Any other wrong secret is just invalid.
+    pub async fn refresh_session(
+        &self,
+        refresh_token: &str,
+    ) -> Result<Option<OauthResponse>, Error> {
+        let (id, secret) = match split_refresh_token(refresh_token) {
+            Some(parts) => parts,
+            None => return Ok(None),
+        };
+
+        let session = match self.db.get_session(id).await? {
+            Some(session) => session,
+            None => return Ok(None),
+        };
+
+        let now = chrono::Utc::now().timestamp();
+
+        if session.revoked || session.expires_at.timestamp_millis() / 1000 <= now {
+            return Ok(None);
+        }
+
+        let hash = crypto::hash_token(secret);
+
+        match check_refresh_hash(&session, &hash) {
+            RefreshHash::Current => {}
+            RefreshHash::Reused => {
+                tracing::warn!(
+                    "refresh token reuse detected for session {} of user {}",
+                    session.id,
+                    session.user_id
+                );
+                self.revoke_session(&session).await?;
+                return Ok(None);
+            }
+            RefreshHash::Unknown => return Ok(None),
+        }
+
+        // new tokens carry the profile cached at login, which is always
+        // present for sessions created through the callback
+        let user = match self.discord_user(&session.user_id).await? {
+            Some(user) => user,
+            None => return Ok(None),
+        };
+
+        let new_secret = crypto::random_token(32);
+        // staff is looked up again, so losing it takes effect on the next refresh
+        let staff = self.is_staff(&user.id).await?;
+        let (token, claims) = self.jwt.create_jwt(&user, &session.id, staff)?;
+        let refresh_expires = now + REFRESH_TOKEN_TTL;
+
+        let rotated = self
+            .db
+            .rotate_session(
+                &session.id,
+                &hash,
+                &crypto::hash_token(&new_secret),
+                &claims.jti,
+                timestamp(refresh_expires),
+            )
+            .await?;
+
+        if rotated.is_none() {
+            // lost a race against a concurrent refresh with the same token
+            return Ok(None);
+        }
+
+        self.revoke_access_token(&session.access_jti).await?;
+
+        Ok(Some(OauthResponse {
+            token,
+            expires: claims.exp,
+            refresh_token: format!("{}.{}", session.id, new_secret),
+            refresh_expires,
+        }))
+    }
+
+    async fn revoke_access_token(&self, jti: &str) -> Result<(), Error> {
+        // an access token can not outlive its TTL, so neither does its entry
+        let expires_at = timestamp(chrono::Utc::now().timestamp() + ACCESS_TOKEN_TTL);
+
+        self.db
+            .revoke_token(RevokedToken {
+                jti: jti.to_string(),
+                expires_at,
+            })
+            .await?;
+
+        Ok(())
+    }
+
+    async fn revoke_session(&self, session: &Session) -> Result<(), Error> {
+        self.db.revoke_session(&session.id).await?;
+        self.revoke_access_token(&session.access_jti).await?;
+
+        Ok(())
+    }
+
+    /// Ends the session the access token in `claims` belongs to.
+    pub async fn logout(&self, claims: &Claims) -> Result<(), Error> {
+        self.db.revoke_session(&claims.sid).await?;
+        self.revoke_access_token(&claims.jti).await?;
+
+        Ok(())
+    }
+
+    /// Ends every session of `user_id`, returning how many were active.
+    pub async fn logout_all(&self, user_id: &str) -> Result<usize, Error> {
+        let sessions = self.db.get_active_sessions(user_id).await?;
+
+        for session in &sessions {
+            self.revoke_session(session).await?;
+        }
+
+        Ok(sessions.len())
+    }
+
+    pub async fn is_token_revoked(&self, claims: &Claims) -> Result<bool, Error> {
+        Ok(self.db.is_token_revoked(&claims.jti).await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session(refresh_hash: &str, previous_hashes: &[&str]) -> Session {
+        let now = bson::DateTime::now();
+
+        Session {
+            id: "sid".to_string(),
+            user_id: "1".to_string(),
+            refresh_hash: refresh_hash.to_string(),
+            previous_hashes: previous_hashes.iter().map(|h| h.to_string()).collect(),
+            access_jti: "jti".to_string(),
+            created_at: now,
+            expires_at: now,
+            revoked: false,
+        }
+    }
+
+    #[test]
+    fn only_rotated_out_tokens_count_as_reuse() {
+        let session = session("c", &["a", "b"]);
+
+        assert_eq!(check_refresh_hash(&session, "c"), RefreshHash::Current);
+        assert_eq!(check_refresh_hash(&session, "a"), RefreshHash::Reused);
+        assert_eq!(check_refresh_hash(&session, "b"), RefreshHash::Reused);
+        // anyone who saw an access token knows the session ID, not a secret
+        assert_eq!(check_refresh_hash(&session, "x"), RefreshHash::Unknown);
+    }
+
+    #[test]
+    fn splits_refresh_tokens() {
+        assert_eq!(split_refresh_token("sid.secret"), Some(("sid", "secret")));
+        assert_eq!(split_refresh_token("sid."), None);
+        assert_eq!(split_refresh_token(".secret"), None);
+        assert_eq!(split_refresh_token("sid"), None);
+    }
+}
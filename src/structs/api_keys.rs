@@ -0,0 +1,21 @@
+use serde::{Deserialize, Serialize};
+
+/// A long-lived credential for scripts and bots. Only a SHA-256 hash of the
+/// secret part of the key is stored.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ApiKey {
+    pub id: String,
+    pub name: String,
+    pub owner_id: String,
+    pub hash: String,
+    pub scopes: Vec<ApiKeyScope>,
+    pub created_at: bson::DateTime,
+    pub revoked: bool,
+}
+
+/// The permission nodes a key may use within one guild.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ApiKeyScope {
+    pub guild_id: String,
+    pub permissions: Vec<String>,
+}
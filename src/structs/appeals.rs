@@ -5,10 +5,15 @@ use serde::{Deserialize, Serialize};
 use serde_with::skip_serializing_none;
 
 use super::AppealContent;
+use crate::crypto;
 
 #[skip_serializing_none]
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Appeal {
+    /// Assigned by the API when the appeal is created, any ID sent by the
+    /// client is replaced.
+    #[serde(default)]
+    pub id: String,
     pub guild_id: String,
     pub user_id: String,
     pub punishment_uuid: String,
@@ -29,8 +34,18 @@ impl From<Appeal> for UpdateModifications {
     }
 }
 
+/// A partial update to an appeal. `content` belongs to the appellant, while
+/// `status` and `status_reason` are reserved for the guild's moderators.
 #[skip_serializing_none]
 #[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AppealUpdate {
+    pub content: Option<Vec<AppealContent>>,
+    pub status: Option<AppealStatus>,
+    pub status_reason: Option<String>,
+}
+
+#[skip_serializing_none]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum AppealStatus {
     Pending,
@@ -56,6 +71,7 @@ impl Appeal {
         content: Vec<AppealContent>,
     ) -> Self {
         Self {
+            id: crypto::random_token(16),
             guild_id,
             user_id,
             punishment_uuid,
@@ -65,3 +81,18 @@ impl Appeal {
         }
     }
 }
+
+/// The Discord webhook a guild posts appeal notifications through. It is a
+/// credential, so it is kept out of the guild config and `url` is encrypted
+/// with the API's `TokenCipher`, bound to `guild_id`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AppealWebhook {
+    pub guild_id: String,
+    pub webhook_id: String,
+    pub url: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct AppealWebhookUpdate {
+    pub url: String,
+}
@@ -64,19 +64,19 @@ impl From<Config> for UpdateModifications {
     }
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct User {
     pub groups: Vec<String>,
     pub permissions: Vec<String>,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Role {
     pub groups: Vec<String>,
     pub permissions: Vec<String>,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Group {
     pub permissions: Vec<String>,
     pub inherit: Vec<String>,
@@ -167,7 +167,7 @@ impl Antinuke {
         if let Some(enabled) = new.enabled {
             antinuke.enabled = Some(enabled);
         }
-        
+
         if let Some(action) = new.action {
             antinuke.action = Some(action);
         }
@@ -183,7 +183,7 @@ impl Antinuke {
         if let Some(triggers) = new.triggers {
             antinuke.triggers = Some(triggers);
         }
-    
+
         antinuke
     }
 }
@@ -269,6 +269,16 @@ pub struct AppealContent {
     answers: Option<Vec<String>>,
 }
 
+impl AppealContent {
+    pub fn question(&self) -> String {
+        self.question.join("\n")
+    }
+
+    pub fn answers(&self) -> &[String] {
+        self.answers.as_deref().unwrap_or_default()
+    }
+}
+
 #[skip_serializing_none]
 #[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
 pub enum AppealContentType {
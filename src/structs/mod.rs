@@ -1,4 +1,10 @@
+mod api_keys;
 mod appeals;
 mod config;
+mod tokens;
+mod users;
+pub use api_keys::*;
 pub use appeals::*;
 pub use config::*;
+pub use tokens::*;
+pub use users::*;
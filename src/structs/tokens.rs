@@ -0,0 +1,55 @@
+use serde::{Deserialize, Serialize};
+
+/// The Discord OAuth tokens of a user, kept so the API can call Discord on
+/// their behalf for as long as their session lives.
+///
+/// `access_token` and `refresh_token` are encrypted with the API's
+/// `TokenCipher`, bound to `user_id`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DiscordToken {
+    pub user_id: String,
+    pub access_token: String,
+    pub refresh_token: String,
+    pub scope: String,
+    pub expires_at: i64,
+}
+
+/// A login session. Each session hands out one rotating refresh token, of
+/// which only a SHA-256 hash is stored, and remembers the access token it
+/// issued last so that token can be revoked alongside the session.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Session {
+    pub id: String,
+    pub user_id: String,
+    pub refresh_hash: String,
+    /// Hashes of the most recent refresh tokens rotated out of the session,
+    /// newest last, so reuse of a leaked one can be told apart from a guess.
+    #[serde(default)]
+    pub previous_hashes: Vec<String>,
+    pub access_jti: String,
+    pub created_at: bson::DateTime,
+    pub expires_at: bson::DateTime,
+    pub revoked: bool,
+}
+
+/// An access token that was revoked before it expired. Entries are removed
+/// by a TTL index once `expires_at` has passed.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RevokedToken {
+    pub jti: String,
+    pub expires_at: bson::DateTime,
+}
+
+/// A pending Discord login, created by `/oauth/login` and consumed by the
+/// callback. Holds the PKCE verifier the code exchange has to present and
+/// where to send the user once they are logged in.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct OauthState {
+    pub state: String,
+    pub code_verifier: String,
+    pub return_to: Option<String>,
+    /// Deliver the session as cookies instead of bearer tokens.
+    #[serde(default)]
+    pub cookie_session: bool,
+    pub expires_at: bson::DateTime,
+}
@@ -0,0 +1,17 @@
+use serde::{Deserialize, Serialize};
+
+/// Global flags of a user, which apply across every guild.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct UserFlags {
+    pub user_id: String,
+    pub flags: Vec<String>,
+}
+
+/// An audit record of a staff member using their staff access on a guild.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StaffAccess {
+    pub user_id: String,
+    pub guild_id: String,
+    pub permission: String,
+    pub at: bson::DateTime,
+}
@@ -0,0 +1,111 @@
+use hyper::{Body, Request, Response};
+use serde::Serialize;
+
+use crate::{
+    auth::Claims, discord::DiscordUser, errors::Error, sessions, structs::StaffAccess, Api,
+};
+
+/// The global flag that makes a user Black Mesa staff.
+pub const STAFF_FLAG: &str = "staff";
+
+/// The user a token belongs to.
+#[derive(Debug, Serialize)]
+pub struct CurrentUser {
+    pub user: DiscordUser,
+    pub token_expires: usize,
+    pub staff: bool,
+    pub flags: Vec<String>,
+}
+
+impl Api {
+    /// Staff are either listed in `STAFF_USER_IDS` or carry the staff flag.
+    pub async fn is_staff(&self, user_id: &str) -> Result<bool, Error> {
+        if self.config.staff_user_ids.iter().any(|id| id == user_id) {
+            return Ok(true);
+        }
+
+        let flags = self.db.get_user_flags(user_id).await?;
+
+        Ok(flags.iter().any(|flag| flag == STAFF_FLAG))
+    }
+
+    /// Writes a staff access to `guild_id` to the audit trail.
+    pub async fn audit_staff_access(
+        &self,
+        user_id: &str,
+        guild_id: &str,
+        permission: &str,
+    ) -> Result<(), Error> {
+        tracing::info!(
+            "staff {} used {} on guild {}",
+            user_id,
+            permission,
+            guild_id
+        );
+
+        self.db
+            .record_staff_access(StaffAccess {
+                user_id: user_id.to_string(),
+                guild_id: guild_id.to_string(),
+                permission: permission.to_string(),
+                at: bson::DateTime::now(),
+            })
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn get_current_user(
+        &self,
+        req: Request<Body>,
+        claims: &Claims,
+    ) -> Result<Response<Body>, hyper::Error> {
+        let user = match self.discord_user(&claims.sub).await {
+            Ok(Some(user)) => user,
+            Ok(None) => return self.not_found().await,
+            Err(e) => {
+                tracing::error!("error getting discord user: {}", e);
+                return self.internal_server_error().await;
+            }
+        };
+
+        let flags = match self.db.get_user_flags(&claims.sub).await {
+            Ok(flags) => flags,
+            Err(e) => {
+                tracing::error!("error getting user flags: {}", e);
+                return self.internal_server_error().await;
+            }
+        };
+
+        self.json_response(
+            req,
+            CurrentUser {
+                user,
+                token_expires: claims.exp,
+                staff: claims.staff,
+                flags,
+            },
+        )
+        .await
+    }
+
+    /// Logs the user out everywhere by ending all of their sessions.
+    pub async fn delete_current_sessions(
+        &self,
+        _req: Request<Body>,
+        claims: &Claims,
+    ) -> Result<Response<Body>, hyper::Error> {
+        match self.logout_all(&claims.sub).await {
+            Ok(_) => {
+                let mut resp = Response::builder().status(204).body(Body::empty()).unwrap();
+                sessions::append_cookies(&mut resp, sessions::clear_session_cookies());
+
+                Ok(resp)
+            }
+            Err(e) => {
+                tracing::error!("error logging out: {}", e);
+                self.internal_server_error().await
+            }
+        }
+    }
+}
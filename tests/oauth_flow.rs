@@ -0,0 +1,181 @@
+//! Drives the login → JWT → guild listing flow of a real API process against
+//! the fake Discord API. Needs a MongoDB to store sessions in, so it only runs
+//! with `MONGO_URI` set:
+//!
+//! ```sh
+//! MONGO_URI=mongodb://localhost:27017 cargo test -- --ignored
+//! ```
+
+use std::{
+    collections::HashMap,
+    net::TcpListener,
+    process::{Child, Command},
+    time::Duration,
+};
+
+use serde_json::Value;
+
+const FAKE_USER_ID: &str = "100000000000000001";
+const FAKE_GUILD_ID: &str = "200000000000000001";
+
+/// Kills the API process when the test ends, however it ends.
+struct Server {
+    child: Child,
+    base_url: String,
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+async fn start_server(mongo_uri: &str) -> Server {
+    let port = free_port();
+    let base_url = format!("http://127.0.0.1:{}", port);
+
+    let child = Command::new(env!("CARGO_BIN_EXE_black-mesa-api"))
+        .env_clear()
+        .env("PORT", port.to_string())
+        .env("FAKE_DISCORD_PORT", free_port().to_string())
+        .env("PUBLIC_URL", &base_url)
+        .env("MONGO_URI", mongo_uri)
+        .env("DISCORD_CLIENT_ID", "1")
+        .env("DISCORD_CLIENT_SECRET", "secret")
+        .env("JWT_SECRET", "oauth-flow-test")
+        .env(
+            "TOKEN_ENCRYPTION_KEY",
+            "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=",
+        )
+        .spawn()
+        .expect("failed to start the API");
+
+    let server = Server { child, base_url };
+
+    for _ in 0..100 {
+        if reqwest::get(&server.base_url).await.is_ok() {
+            return server;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    panic!("the API did not start listening");
+}
+
+fn location(resp: &reqwest::Response) -> url::Url {
+    assert_eq!(resp.status(), 302, "expected a redirect");
+    url::Url::parse(resp.headers()["Location"].to_str().unwrap()).unwrap()
+}
+
+#[tokio::test]
+#[ignore = "needs MongoDB, run with MONGO_URI set and --ignored"]
+async fn login_then_list_guilds() {
+    let mongo_uri = std::env::var("MONGO_URI").expect("MONGO_URI must be set");
+    let server = start_server(&mongo_uri).await;
+
+    let http = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .unwrap();
+
+    // the API sends the browser to the fake authorization screen
+    let login = http
+        .get(format!("{}/oauth/login", server.base_url))
+        .send()
+        .await
+        .unwrap();
+    let authorize = location(&login);
+    let state_cookie = login.headers()["Set-Cookie"]
+        .to_str()
+        .unwrap()
+        .split(';')
+        .next()
+        .unwrap()
+        .to_string();
+
+    // which approves straight away and sends it back to the callback
+    let approved = http.get(authorize).send().await.unwrap();
+    let callback = location(&approved);
+    assert_eq!(callback.path(), "/oauth/callback");
+
+    let session: HashMap<String, Value> = http
+        .get(callback)
+        .header("Cookie", state_cookie)
+        .send()
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let token = session["token"].as_str().unwrap().to_string();
+
+    let me: Value = http
+        .get(format!("{}/api/v1/users/@me", server.base_url))
+        .bearer_auth(&token)
+        .send()
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(me["user"]["id"], FAKE_USER_ID);
+
+    // only guilds with a config are listed, the fake user owns every fake
+    // guild so they can bootstrap one (409 when a previous run left it behind)
+    let created = http
+        .post(format!(
+            "{}/api/v1/guild/{}",
+            server.base_url, FAKE_GUILD_ID
+        ))
+        .bearer_auth(&token)
+        .json(&serde_json::json!({}))
+        .send()
+        .await
+        .unwrap();
+    assert!(
+        created.status() == 201 || created.status() == 409,
+        "bootstrapping the guild failed with {}",
+        created.status()
+    );
+
+    let guilds: Vec<Value> = http
+        .get(format!("{}/api/v1/guilds?refresh=true", server.base_url))
+        .bearer_auth(&token)
+        .send()
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let guild = guilds
+        .iter()
+        .find(|guild| guild["id"] == FAKE_GUILD_ID)
+        .expect("the bootstrapped guild is listed");
+    assert_eq!(guild["can_manage"], true);
+
+    let deleted = http
+        .delete(format!(
+            "{}/api/v1/guild/{}",
+            server.base_url, FAKE_GUILD_ID
+        ))
+        .bearer_auth(&token)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(deleted.status(), 204);
+}